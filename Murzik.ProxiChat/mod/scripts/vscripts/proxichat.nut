@@ -0,0 +1,45 @@
+global function ProxiChat_Init
+
+void function ProxiChat_Init()
+{
+	AddCallback_OnClientConnected( ProxiChat_OnClientConnected )
+	AddCallback_OnClientDisconnected( ProxiChat_OnClientDisconnected )
+
+	thread ProxiChat_UpdatePlayers()
+}
+
+void function ProxiChat_OnClientConnected( entity player )
+{
+	// tells the client's plugin where the voice server is, before the token so it connects to the right port
+	ServerToClientStringCommand( player, "proxichat_voice_port " + ProxiChat_GetVoicePort() )
+
+	// the token proves to the voice server that the client is this player
+	string token = ProxiChat_GetAuthToken( player.GetUID() )
+	if ( token != "" )
+		ServerToClientStringCommand( player, "proxichat_auth_token " + token )
+}
+
+void function ProxiChat_OnClientDisconnected( entity player )
+{
+	ProxiChat_RemovePlayer( player.GetUID() )
+}
+
+void function ProxiChat_UpdatePlayers()
+{
+	while ( true )
+	{
+		WaitFrame()
+
+		foreach ( entity player in GetPlayerArray() )
+		{
+			bool alive = IsAlive( player ) && player.GetTeam() != TEAM_SPECTATOR
+
+			string observerTarget = ""
+			entity target = player.GetObserverTarget()
+			if ( !alive && IsValid( target ) && target.IsPlayer() )
+				observerTarget = target.GetUID()
+
+			ProxiChat_UpdatePlayer( player.GetUID(), player.GetOrigin(), player.EyeAngles(), player.GetTeam(), alive, observerTarget )
+		}
+	}
+}
@@ -0,0 +1,34 @@
+global function ProxiChat_ClientInit
+
+void function ProxiChat_ClientInit()
+{
+	// string commands from the server only reach callbacks registered here, the plugin can't get them otherwise
+	AddServerToClientStringCommandCallback( "proxichat_voice_port", ProxiChat_OnVoicePort )
+	AddServerToClientStringCommandCallback( "proxichat_auth_token", ProxiChat_OnAuthToken )
+
+	thread ProxiChat_ShowMessages()
+}
+
+void function ProxiChat_ShowMessages()
+{
+	while ( true )
+	{
+		WaitFrame()
+
+		string message = ProxiChat_PopMessage()
+		if ( message != "" )
+			Chat_GameWriteLine( "proximity chat: " + message )
+	}
+}
+
+void function ProxiChat_OnVoicePort( array<string> args )
+{
+	if ( args.len() > 0 )
+		ProxiChat_SetVoicePort( args[0] )
+}
+
+void function ProxiChat_OnAuthToken( array<string> args )
+{
+	if ( args.len() > 0 )
+		ProxiChat_SetAuthToken( args[0] )
+}
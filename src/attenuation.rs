@@ -0,0 +1,168 @@
+use std::{fmt, str::FromStr};
+
+use crate::mixer::MAX_HEARING_DISTANCE;
+
+/// how fast a voice fades between the min and max distance
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Rolloff {
+    Linear,
+    Inverse,
+    InverseSquare,
+    Logarithmic,
+}
+
+impl FromStr for Rolloff {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "linear" => Ok(Rolloff::Linear),
+            "inverse" => Ok(Rolloff::Inverse),
+            "inverse_square" => Ok(Rolloff::InverseSquare),
+            "logarithmic" => Ok(Rolloff::Logarithmic),
+            _ => Err(format!(
+                "unknown rolloff {s}; expected linear, inverse, inverse_square or logarithmic"
+            )),
+        }
+    }
+}
+
+impl fmt::Display for Rolloff {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Rolloff::Linear => "linear",
+            Rolloff::Inverse => "inverse",
+            Rolloff::InverseSquare => "inverse_square",
+            Rolloff::Logarithmic => "logarithmic",
+        })
+    }
+}
+
+/// voices are at full volume under `min_distance` and silent past `max_distance` (hammer units)
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Attenuation {
+    pub min_distance: f32,
+    pub max_distance: f32,
+    pub rolloff: Rolloff,
+}
+
+impl Default for Attenuation {
+    fn default() -> Self {
+        Self {
+            min_distance: 100.,
+            max_distance: MAX_HEARING_DISTANCE,
+            rolloff: Rolloff::Linear,
+        }
+    }
+}
+
+impl Attenuation {
+    pub fn new(rolloff: Rolloff, min_distance: f32, max_distance: f32) -> Result<Self, String> {
+        if !(min_distance > 0. && max_distance > min_distance) {
+            return Err(format!(
+                "the distances have to be 0 < min < max, got {min_distance} and {max_distance}"
+            ));
+        }
+
+        Ok(Self {
+            min_distance,
+            max_distance,
+            rolloff,
+        })
+    }
+
+    /// always in `0..=1`, a distance that isn't a number is treated as out of range
+    pub fn gain(&self, distance: f32) -> f32 {
+        if distance.is_nan() {
+            return 0.;
+        }
+        if distance <= self.min_distance {
+            return 1.;
+        }
+        if distance >= self.max_distance {
+            return 0.;
+        }
+
+        match self.rolloff {
+            Rolloff::Linear => {
+                1. - (distance - self.min_distance) / (self.max_distance - self.min_distance)
+            }
+            Rolloff::Inverse => self.min_distance / distance,
+            Rolloff::InverseSquare => (self.min_distance / distance).powi(2),
+            Rolloff::Logarithmic => {
+                1. - (distance / self.min_distance).ln()
+                    / (self.max_distance / self.min_distance).ln()
+            }
+        }
+    }
+}
+
+impl fmt::Display for Attenuation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} from {} to {}",
+            self.rolloff, self.min_distance, self.max_distance
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ROLLOFFS: [Rolloff; 4] = [
+        Rolloff::Linear,
+        Rolloff::Inverse,
+        Rolloff::InverseSquare,
+        Rolloff::Logarithmic,
+    ];
+
+    #[test]
+    fn full_volume_up_to_min_distance() {
+        for rolloff in ROLLOFFS {
+            let attenuation = Attenuation::new(rolloff, 100., 1000.).unwrap();
+            assert_eq!(attenuation.gain(0.), 1.);
+            assert_eq!(attenuation.gain(-1.), 1.);
+            assert_eq!(attenuation.gain(100.), 1.);
+        }
+    }
+
+    #[test]
+    fn silent_from_max_distance() {
+        for rolloff in ROLLOFFS {
+            let attenuation = Attenuation::new(rolloff, 100., 1000.).unwrap();
+            assert_eq!(attenuation.gain(1000.), 0.);
+            assert_eq!(attenuation.gain(f32::INFINITY), 0.);
+            assert_eq!(attenuation.gain(f32::NAN), 0.);
+        }
+    }
+
+    #[test]
+    fn fades_in_between() {
+        for rolloff in ROLLOFFS {
+            let attenuation = Attenuation::new(rolloff, 100., 1000.).unwrap();
+            let gains = (101..1000)
+                .map(|distance| attenuation.gain(distance as f32))
+                .collect::<Vec<f32>>();
+
+            assert!(
+                gains.iter().all(|gain| (0. ..1.).contains(gain)),
+                "{rolloff}"
+            );
+            assert!(gains.windows(2).all(|pair| pair[1] <= pair[0]), "{rolloff}");
+        }
+
+        let linear = Attenuation::new(Rolloff::Linear, 100., 1000.).unwrap();
+        assert_eq!(linear.gain(550.), 0.5);
+    }
+
+    #[test]
+    fn refuses_invalid_distances() {
+        assert!(Attenuation::new(Rolloff::Linear, 0., 1000.).is_err());
+        assert!(Attenuation::new(Rolloff::Linear, 1000., 1000.).is_err());
+        assert!(Attenuation::new(Rolloff::Linear, 1000., 100.).is_err());
+        assert!(Attenuation::new(Rolloff::Linear, f32::NAN, 1000.).is_err());
+        assert!(Attenuation::new(Rolloff::Linear, 100., f32::NAN).is_err());
+    }
+}
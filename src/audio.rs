@@ -0,0 +1,184 @@
+use cpal::{
+    traits::{DeviceTrait, HostTrait, StreamTrait},
+    Device, FromSample, InputCallbackInfo, OutputCallbackInfo, SampleFormat, SizedSample, Stream,
+    StreamConfig,
+};
+use parking_lot::Mutex;
+use std::sync::{mpsc::Sender, Arc};
+
+use crate::{
+    jitter::JitterBuffer,
+    resample::{deinterleave, downmix, upmix_stereo, Resampler},
+    shared::{
+        AudioSampleType, AudioSampleVec, ProxiChatError, AUDIO_BUFFER_SIZE, DEFAULT_FILL_SAMPLE,
+        OUTPUT_CHANNELS, WIRE_SAMPLE_RATE,
+    },
+};
+
+/// plays the stereo jitter buffer on the default output device, whatever its sample format is
+///
+/// what gets played is also sent to `reference` in mono for the echo canceller
+pub fn build_output_stream(
+    playback: Arc<Mutex<JitterBuffer>>,
+    reference: Sender<AudioSampleVec>,
+) -> Result<Stream, ProxiChatError> {
+    let device = cpal::default_host()
+        .default_output_device()
+        .ok_or(ProxiChatError::NoAudioDevice("output"))?;
+    let supported_config = device.default_output_config()?;
+    let config = supported_config.config();
+
+    log::info!(
+        "output: {} channels at {} hz as {}",
+        config.channels,
+        config.sample_rate.0,
+        supported_config.sample_format()
+    );
+
+    let stream = match supported_config.sample_format() {
+        SampleFormat::F32 => output_stream::<f32>(&device, &config, playback, reference)?,
+        SampleFormat::F64 => output_stream::<f64>(&device, &config, playback, reference)?,
+        SampleFormat::I8 => output_stream::<i8>(&device, &config, playback, reference)?,
+        SampleFormat::I16 => output_stream::<i16>(&device, &config, playback, reference)?,
+        SampleFormat::I32 => output_stream::<i32>(&device, &config, playback, reference)?,
+        SampleFormat::U8 => output_stream::<u8>(&device, &config, playback, reference)?,
+        SampleFormat::U16 => output_stream::<u16>(&device, &config, playback, reference)?,
+        SampleFormat::U32 => output_stream::<u32>(&device, &config, playback, reference)?,
+        format => Err(ProxiChatError::UnsupportedSampleFormat(format))?,
+    };
+    stream.play()?;
+
+    Ok(stream)
+}
+
+/// captures the default input device and sends mono `WIRE_SAMPLE_RATE` audio to `sender`
+pub fn build_input_stream(sender: Sender<AudioSampleVec>) -> Result<Stream, ProxiChatError> {
+    let device = cpal::default_host()
+        .default_input_device()
+        .ok_or(ProxiChatError::NoAudioDevice("input"))?;
+    let supported_config = device.default_input_config()?;
+    let config = supported_config.config();
+
+    log::info!(
+        "input: {} channels at {} hz as {}",
+        config.channels,
+        config.sample_rate.0,
+        supported_config.sample_format()
+    );
+
+    let stream = match supported_config.sample_format() {
+        SampleFormat::F32 => input_stream::<f32>(&device, &config, sender)?,
+        SampleFormat::F64 => input_stream::<f64>(&device, &config, sender)?,
+        SampleFormat::I8 => input_stream::<i8>(&device, &config, sender)?,
+        SampleFormat::I16 => input_stream::<i16>(&device, &config, sender)?,
+        SampleFormat::I32 => input_stream::<i32>(&device, &config, sender)?,
+        SampleFormat::U8 => input_stream::<u8>(&device, &config, sender)?,
+        SampleFormat::U16 => input_stream::<u16>(&device, &config, sender)?,
+        SampleFormat::U32 => input_stream::<u32>(&device, &config, sender)?,
+        format => Err(ProxiChatError::UnsupportedSampleFormat(format))?,
+    };
+    stream.play()?;
+
+    Ok(stream)
+}
+
+fn output_stream<T>(
+    device: &Device,
+    config: &StreamConfig,
+    playback: Arc<Mutex<JitterBuffer>>,
+    reference: Sender<AudioSampleVec>,
+) -> Result<Stream, ProxiChatError>
+where
+    T: SizedSample + FromSample<AudioSampleType>,
+{
+    // the mix is stereo at WIRE_SAMPLE_RATE so it has to be converted to what the device wants
+    let channels = config.channels as usize;
+    let mut resamplers =
+        [(); OUTPUT_CHANNELS].map(|_| Resampler::new(WIRE_SAMPLE_RATE, config.sample_rate.0));
+    let mut wire_frame = vec![DEFAULT_FILL_SAMPLE; AUDIO_BUFFER_SIZE * OUTPUT_CHANNELS];
+    let mut wire_channel = AudioSampleVec::with_capacity(AUDIO_BUFFER_SIZE);
+    let mut resampled = [(); OUTPUT_CHANNELS].map(|_| AudioSampleVec::new());
+    let mut device_buffer = AudioSampleVec::new();
+
+    Ok(device.build_output_stream(
+        config,
+        move |data: &mut [T], _: &OutputCallbackInfo| {
+            let frames = data.len() / channels.max(1);
+
+            let mut playback = playback.lock();
+            while resampled[0].len() < frames {
+                playback.fill(&mut wire_frame);
+
+                let mut mono = AudioSampleVec::with_capacity(AUDIO_BUFFER_SIZE);
+                downmix(&wire_frame, OUTPUT_CHANNELS, &mut mono);
+                _ = reference.send(mono);
+
+                for (channel, (resampler, resampled)) in
+                    resamplers.iter_mut().zip(resampled.iter_mut()).enumerate()
+                {
+                    wire_channel.clear();
+                    deinterleave(&wire_frame, OUTPUT_CHANNELS, channel, &mut wire_channel);
+                    resampler.process(&wire_channel, resampled);
+                }
+            }
+            drop(playback);
+
+            device_buffer.clear();
+            device_buffer.resize(data.len(), DEFAULT_FILL_SAMPLE);
+            upmix_stereo(
+                &resampled[0][..frames],
+                &resampled[1][..frames],
+                channels,
+                &mut device_buffer,
+            );
+            resampled
+                .iter_mut()
+                .for_each(|resampled| _ = resampled.drain(..frames));
+
+            for (sample, new_sample) in data.iter_mut().zip(device_buffer.iter()) {
+                *sample = T::from_sample(*new_sample);
+            }
+        },
+        |err| {
+            log::error!("output stream error: {err}");
+        },
+        None,
+    )?)
+}
+
+fn input_stream<T>(
+    device: &Device,
+    config: &StreamConfig,
+    sender: Sender<AudioSampleVec>,
+) -> Result<Stream, ProxiChatError>
+where
+    T: SizedSample,
+    AudioSampleType: FromSample<T>,
+{
+    let channels = config.channels as usize;
+    let mut resampler = Resampler::new(config.sample_rate.0, WIRE_SAMPLE_RATE);
+    let mut device_buffer = AudioSampleVec::new();
+    let mut mono = AudioSampleVec::new();
+
+    Ok(device.build_input_stream(
+        config,
+        move |data: &[T], _: &InputCallbackInfo| {
+            device_buffer.clear();
+            device_buffer.extend(
+                data.iter()
+                    .map(|sample| sample.to_sample::<AudioSampleType>()),
+            );
+
+            mono.clear();
+            downmix(&device_buffer, channels, &mut mono);
+
+            let mut wire_audio = AudioSampleVec::with_capacity(mono.len() * 2);
+            resampler.process(&mono, &mut wire_audio);
+            _ = sender.send(wire_audio);
+        },
+        |err| {
+            log::error!("input stream error: {err}");
+        },
+        None,
+    )?)
+}
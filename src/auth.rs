@@ -0,0 +1,43 @@
+use std::{fmt, str::FromStr};
+
+use crate::shared::ProxiChatError;
+
+/// a secret the server gives a player in game, their voice client needs it for the key exchange
+///
+/// since only the player's game connection gets it nobody else can claim their uid
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct AuthToken(u128);
+
+impl AuthToken {
+    pub fn generate() -> Result<Self, ProxiChatError> {
+        let mut bytes = [0; 16];
+        getrandom::getrandom(&mut bytes)?;
+
+        Ok(Self(u128::from_le_bytes(bytes)))
+    }
+
+    pub fn to_bytes(self) -> [u8; 16] {
+        self.0.to_le_bytes()
+    }
+}
+
+impl fmt::Display for AuthToken {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:032x}", self.0)
+    }
+}
+
+// the token shouldn't end up in logs
+impl fmt::Debug for AuthToken {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("AuthToken(..)")
+    }
+}
+
+impl FromStr for AuthToken {
+    type Err = ProxiChatError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(Self(u128::from_str_radix(s, 16)?))
+    }
+}
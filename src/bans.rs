@@ -0,0 +1,96 @@
+use std::{collections::HashSet, fs, io, path::PathBuf};
+
+use crate::shared::{profile_dir, ProxiChatError};
+
+const BAN_FILE: &str = "proxichat_bans.txt";
+
+/// uids that can't use voice on this server, one per line in the profile folder
+#[derive(Debug)]
+pub struct BanList {
+    path: PathBuf,
+    uids: HashSet<i64>,
+}
+
+impl BanList {
+    /// a missing file is an empty list
+    pub fn load() -> Self {
+        Self::load_from(profile_dir().join(BAN_FILE))
+    }
+
+    pub(crate) fn load_from(path: PathBuf) -> Self {
+        let uids = match fs::read_to_string(&path) {
+            Ok(bans) => bans
+                .lines()
+                .map(str::trim)
+                .filter(|line| !line.is_empty())
+                .filter_map(|uid| {
+                    uid.parse()
+                        .map_err(|err| log::warn!("invalid uid {uid} in {}: {err}", path.display()))
+                        .ok()
+                })
+                .collect(),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => HashSet::new(),
+            Err(err) => {
+                log::error!("couldn't read {}: {err}", path.display());
+                HashSet::new()
+            }
+        };
+
+        Self { path, uids }
+    }
+
+    pub fn contains(&self, uid: i64) -> bool {
+        self.uids.contains(&uid)
+    }
+
+    pub fn ban(&mut self, uid: i64) -> Result<bool, ProxiChatError> {
+        let added = self.uids.insert(uid);
+        self.save()?;
+        Ok(added)
+    }
+
+    pub fn unban(&mut self, uid: i64) -> Result<bool, ProxiChatError> {
+        let removed = self.uids.remove(&uid);
+        self.save()?;
+        Ok(removed)
+    }
+
+    fn save(&self) -> Result<(), ProxiChatError> {
+        let bans = self
+            .uids
+            .iter()
+            .map(|uid| format!("{uid}\n"))
+            .collect::<String>();
+
+        Ok(fs::write(&self.path, bans)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bans_round_trip() {
+        let path = std::env::temp_dir().join("proxichat_bans_test.txt");
+        _ = fs::remove_file(&path);
+
+        let mut bans = BanList::load_from(path.clone());
+        assert!(!bans.contains(1005));
+        assert!(bans.ban(1005).unwrap());
+        assert!(bans.ban(-3).unwrap());
+        assert!(!bans.ban(1005).unwrap());
+
+        let mut bans = BanList::load_from(path.clone());
+        assert!(bans.contains(1005));
+        assert!(bans.contains(-3));
+        assert!(bans.unban(1005).unwrap());
+        assert!(!bans.unban(1005).unwrap());
+
+        let bans = BanList::load_from(path.clone());
+        assert!(!bans.contains(1005));
+        assert!(bans.contains(-3));
+
+        _ = fs::remove_file(&path);
+    }
+}
@@ -1,50 +1,199 @@
-use cpal::{
-    traits::{DeviceTrait, HostTrait},
-    InputCallbackInfo, Stream,
-};
+use cpal::Stream;
+use parking_lot::Mutex;
 use rrplug::high::Handle;
 use std::{
-    io::{Read, Write},
-    net::{SocketAddr, TcpStream},
-    str::FromStr,
-    sync::mpsc::{self, Receiver, Sender},
-    time::Duration,
+    collections::{HashMap, VecDeque},
+    net::{TcpStream, ToSocketAddrs, UdpSocket},
+    sync::{
+        mpsc::{self, Receiver},
+        Arc,
+    },
+    time::{Duration, Instant},
 };
 
 use crate::{
+    audio::{build_input_stream, build_output_stream},
+    auth::AuthToken,
     bindings::parse_local_uid,
+    codec::{Codec, VoiceDecoder, VoiceEncoder, SUPPORTED_CODECS},
+    crypto::{Handshake, OpeningKey, SealingKey, Side},
+    denoise::Denoiser,
+    dynamics::{Agc, Limiter},
+    echo::EchoCanceller,
+    framing::{PacketReader, PacketWriter},
+    gate::CaptureGate,
+    heartbeat::{idle_timeout, Heartbeat},
+    jitter::JitterBuffer,
+    protocol::{Capabilities, Features, DEFAULT_BITRATE, PROTOCOL_VERSION},
     shared::{
-        AudioSampleType, AudioSampleVec, NetPacket, ProxiChatError, AUDIO_BUFFER_SIZE,
-        DEFAULT_FILL_SAMPLE, READ_BUFFER_SIZE,
+        AudioSampleVec, NetPacket, ProxiChatError, AUDIO_BUFFER_SIZE, DEFAULT_FILL_SAMPLE,
+        OUTPUT_CHANNELS, PROXICHAT_PORT, WIRE_SAMPLE_RATE,
     },
+    spatial::{add_centered, spatialize, EarFilters},
+    voice::{recv_voice, send_voice, VoicePacket, VOICE_READ_BUFFER_SIZE},
+    volumes::{SpeakerVolume, SpeakerVolumes},
 };
 
+const VOICE_HELLO_INTERVAL: Duration = Duration::from_secs(1);
+/// a speaker still counts as talking this long after their last frame
+const TALKING_HOLD: Duration = Duration::from_millis(300);
+/// older messages are dropped if the scripts don't pick them up
+const MAX_MESSAGES: usize = 8;
+
 pub struct Client {
     tcp_stream: Option<TcpStream>,
+    voice_socket: Option<UdpSocket>,
     ouput_stream: Option<Handle<Stream>>,
     input_stream: Option<Handle<Stream>>,
-    send_audio: Sender<AudioSampleVec>,
+    playback: Arc<Mutex<JitterBuffer>>,
     recv_audio: Receiver<AudioSampleVec>,
-    read_buffer: Vec<u8>,
+    recv_reference: Receiver<AudioSampleVec>,
+    reader: PacketReader,
+    writer: PacketWriter,
+    voice: VoiceState,
     audio_buffer: AudioSampleVec,
-    auth_completed: bool,
+    auth: AuthState,
+    heartbeat: Heartbeat,
+    /// `-proxichat_timeout`
+    idle_timeout: Duration,
+    /// given by the game server, the key exchange waits for it
+    auth_token: Option<AuthToken>,
+    session: Option<u64>,
+    /// what the server agreed to during auth
+    features: Features,
     uid: i64,
+    /// an ip or a hostname without the port
+    server_host: Option<String>,
+    voice_port: u16,
+    radio: bool,
+    talking: bool,
+    gate: CaptureGate,
+    denoiser: Denoiser,
+    echo_canceller: Option<EchoCanceller>,
+    volumes: SpeakerVolumes,
+    /// shown to the player by the client scripts
+    messages: VecDeque<String>,
+}
+
+#[derive(Debug, Default)]
+pub(crate) enum AuthState {
+    #[default]
+    None,
+    /// waiting for the server's half of the key exchange
+    KeyExchangeSent(Handshake, AuthToken),
+    /// the stream is encrypted from now on and the auth can be sent
+    KeyExchanged(SealingKey),
+    AuthSent,
+}
+
+#[derive(Debug)]
+pub(crate) struct VoiceState {
+    sequence: u32,
+    sealing: Option<SealingKey>,
+    opening: Option<OpeningKey>,
+    last_hello: Option<Instant>,
+    encoder: VoiceEncoder,
+    codec: Codec,
+    /// every speaker's audio is decoded and spatialized on its own
+    speakers: HashMap<i64, SpeakerPlayback>,
+    /// when each speaker was last heard
+    speaking: HashMap<i64, Instant>,
+    mix: AudioSampleVec,
+    limiter: Limiter,
+    encoded_buffer: Vec<u8>,
+    send_buffer: Vec<u8>,
+    read_buffer: Vec<u8>,
+}
+
+/// what is kept of a speaker between frames
+#[derive(Debug)]
+struct SpeakerPlayback {
+    decoder: VoiceDecoder,
+    audio: AudioSampleVec,
+    /// the delayed ear reads the end of it
+    previous_audio: AudioSampleVec,
+    filters: EarFilters,
+    last_sequence: Option<u32>,
+}
+
+impl SpeakerPlayback {
+    fn new(codec: Codec) -> Self {
+        Self {
+            decoder: VoiceDecoder::new(codec),
+            audio: vec![DEFAULT_FILL_SAMPLE; AUDIO_BUFFER_SIZE],
+            previous_audio: vec![DEFAULT_FILL_SAMPLE; AUDIO_BUFFER_SIZE],
+            filters: EarFilters::default(),
+            last_sequence: None,
+        }
+    }
+
+    /// decodes the speaker's next frame, the one before is kept for the delayed ear
+    fn decode(&mut self, sequence: u32, data: &[u8]) -> Result<(), ProxiChatError> {
+        std::mem::swap(&mut self.audio, &mut self.previous_audio);
+        // the speaker was silent or the frame was lost
+        if self.last_sequence.map(|last| last.wrapping_add(1)) != Some(sequence) {
+            self.previous_audio.fill(DEFAULT_FILL_SAMPLE);
+        }
+        self.last_sequence = Some(sequence);
+
+        self.decoder.decode(data, &mut self.audio)
+    }
+}
+
+impl Default for VoiceState {
+    fn default() -> Self {
+        Self {
+            sequence: 0,
+            sealing: None,
+            opening: None,
+            last_hello: None,
+            encoder: VoiceEncoder::new(SUPPORTED_CODECS[0], DEFAULT_BITRATE),
+            codec: SUPPORTED_CODECS[0],
+            speakers: HashMap::new(),
+            speaking: HashMap::new(),
+            mix: AudioSampleVec::with_capacity(AUDIO_BUFFER_SIZE * OUTPUT_CHANNELS),
+            limiter: Limiter::default(),
+            encoded_buffer: Vec::new(),
+            send_buffer: Vec::new(),
+            read_buffer: vec![0; VOICE_READ_BUFFER_SIZE],
+        }
+    }
 }
 
 impl Default for Client {
     fn default() -> Self {
-        let (sender, recv) = mpsc::channel();
+        let (_, recv) = mpsc::channel();
+        let (_, recv_reference) = mpsc::channel();
+        let idle_timeout = idle_timeout();
 
         Self {
             tcp_stream: Default::default(),
+            voice_socket: Default::default(),
             ouput_stream: Default::default(),
             input_stream: Default::default(),
-            send_audio: sender,
+            playback: Arc::new(Mutex::new(JitterBuffer::new(WIRE_SAMPLE_RATE))),
             recv_audio: recv,
-            read_buffer: vec![0; READ_BUFFER_SIZE],
-            audio_buffer: vec![DEFAULT_FILL_SAMPLE; AUDIO_BUFFER_SIZE * 4],
-            auth_completed: false,
+            recv_reference,
+            reader: PacketReader::default(),
+            writer: PacketWriter::default(),
+            voice: VoiceState::default(),
+            audio_buffer: AudioSampleVec::with_capacity(AUDIO_BUFFER_SIZE * 4),
+            auth: AuthState::None,
+            heartbeat: Heartbeat::new(idle_timeout),
+            idle_timeout,
+            auth_token: None,
+            session: None,
+            features: Features::NONE,
             uid: 0,
+            server_host: None,
+            voice_port: PROXICHAT_PORT,
+            radio: false,
+            talking: false,
+            gate: CaptureGate::default(),
+            denoiser: Denoiser::default(),
+            echo_canceller: None,
+            volumes: SpeakerVolumes::load(),
+            messages: VecDeque::new(),
         }
     }
 }
@@ -53,6 +202,7 @@ impl std::fmt::Debug for Client {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         f.debug_struct("Client")
             .field("tcp_stream", &self.tcp_stream)
+            .field("voice_socket", &self.voice_socket)
             .field("ouput_stream", &self.ouput_stream.is_some())
             .field("input_stream", &self.input_stream.is_some())
             .finish()
@@ -60,121 +210,240 @@ impl std::fmt::Debug for Client {
 }
 
 impl Client {
-    pub fn set_new_connection(&mut self, addr: String) {
-        self.drop_stream();
+    /// connects to the default port right away, the server may announce another one later
+    pub fn set_new_server(&mut self, host: String) {
+        self.voice_port = PROXICHAT_PORT;
+        self.auth_token = None;
+        self.set_new_connection(&host, self.voice_port);
+        self.server_host = Some(host);
+    }
 
-        self.uid = parse_local_uid().expect("couldn't get local player uid");
-
-        match TcpStream::connect_timeout(
-            &SocketAddr::from_str(&addr)
-                .expect("provided addrress was somehow invalid (impossible)"),
-            Duration::from_secs(1),
-        ) {
-            Ok(stream) => {
-                // stream
-                //     .set_nonblocking(true)
-                //     .expect("couldn't set non blocking stream");
-                self.tcp_stream = stream.into();
-            }
-            Err(err) => return log::error!("couldn't connect to server: {err}"),
+    pub fn set_voice_port(&mut self, port: u16) {
+        let Some(host) = self.server_host.clone() else {
+            return log::warn!("got a voice port without a server to connect to");
+        };
+
+        if port == self.voice_port && self.tcp_stream.is_some() {
+            return;
         }
 
-        let host = cpal::default_host();
+        self.voice_port = port;
+        self.set_new_connection(&host, port);
+    }
 
-        // ouput
-        let device = host
-            .default_output_device()
-            .expect("no output device available!");
+    /// the connection authenticates once it has the token
+    ///
+    /// reconnects if the connection timed out while the game was still loading
+    pub fn set_auth_token(&mut self, token: AuthToken) {
+        self.auth_token = Some(token);
 
-        let supported_configs_range = device
-            .supported_output_configs()
-            .expect("error while querying configs");
-        let supported_config = supported_configs_range
-            .inspect(|c| {
-                log::info!(
-                    "sample format {}",
-                    c.clone().with_max_sample_rate().sample_format()
-                )
-            })
-            .last()
-            .expect("no supported config?!")
-            .with_max_sample_rate();
-        let config = supported_config.config();
+        if self.tcp_stream.is_none() {
+            if let Some(host) = self.server_host.clone() {
+                self.set_new_connection(&host, self.voice_port);
+            }
+        }
+    }
 
-        let (sender, recv) = mpsc::channel();
-        self.send_audio = sender;
-
-        let ouput_stream = device
-            .build_output_stream(
-                &config,
-                move |data: &mut [AudioSampleType], _: &cpal::OutputCallbackInfo| {
-                    for (sample, new_sample) in
-                        data.iter_mut().zip(recv.try_recv().unwrap_or_default())
-                    {
-                        *sample = new_sample;
-                    }
-                },
-                |err| {
-                    log::error!("output stream error: {err}");
-                },
-                None,
-            )
-            .expect("failed to create a output stream");
-        self.ouput_stream = unsafe { Handle::new(ouput_stream) }.into();
-
-        // input
-        let device = host
-            .default_input_device()
-            .expect("no output device available!");
-
-        let mut supported_configs_range = device
-            .supported_input_configs()
-            .expect("error while querying configs");
-        let supported_config = supported_configs_range
-            .next()
-            .expect("no supported config?!")
-            .with_max_sample_rate();
-        let config = supported_config.config();
+    /// `host` is resolved here so hostnames and ipv6 servers work too
+    pub fn set_new_connection(&mut self, host: &str, port: u16) {
+        self.send_disconnect("reconnecting");
+        self.drop_stream();
+
+        self.uid = match parse_local_uid() {
+            Ok(uid) => uid,
+            Err(err) => return log::error!("couldn't get the local player's uid: {err}"),
+        };
+
+        let addrs = match (host, port).to_socket_addrs() {
+            Ok(addrs) => addrs,
+            Err(err) => return log::error!("couldn't resolve {host}: {err}"),
+        };
+
+        let Some((stream, addr)) = addrs.into_iter().find_map(|addr| {
+            TcpStream::connect_timeout(&addr, Duration::from_secs(1))
+                .map(|stream| (stream, addr))
+                .map_err(|err| log::warn!("couldn't connect to {addr}: {err}"))
+                .ok()
+        }) else {
+            return log::error!("couldn't connect to server {host}:{port}");
+        };
+
+        if let Err(err) = stream.set_nonblocking(true) {
+            return log::error!("couldn't set non blocking stream: {err}");
+        }
+        self.tcp_stream = stream.into();
+
+        let local_addr = if addr.is_ipv4() {
+            "0.0.0.0:0"
+        } else {
+            "[::]:0"
+        };
+        match UdpSocket::bind(local_addr).and_then(|socket| {
+            socket.connect(addr)?;
+            socket.set_nonblocking(true)?;
+            Ok(socket)
+        }) {
+            Ok(socket) => self.voice_socket = socket.into(),
+            Err(err) => {
+                self.drop_stream();
+                return log::error!("couldn't create the voice socket: {err}");
+            }
+        }
 
+        self.playback = Arc::new(Mutex::new(JitterBuffer::new(WIRE_SAMPLE_RATE)));
         let (sender, recv) = mpsc::channel();
         self.recv_audio = recv;
+        let (reference_sender, recv_reference) = mpsc::channel();
+        self.recv_reference = recv_reference;
+
+        match build_output_stream(Arc::clone(&self.playback), reference_sender) {
+            Ok(stream) => self.ouput_stream = unsafe { Handle::new(stream) }.into(),
+            Err(err) => {
+                self.drop_stream();
+                return log::error!("couldn't create the output stream: {err}");
+            }
+        }
+
+        match build_input_stream(sender) {
+            Ok(stream) => self.input_stream = unsafe { Handle::new(stream) }.into(),
+            Err(err) => {
+                self.drop_stream();
+                log::error!("couldn't create the input stream: {err}");
+            }
+        }
+    }
+
+    /// while set the voice is sent to the whole team over the radio
+    pub fn set_radio(&mut self, radio: bool) {
+        self.radio = radio;
+    }
+
+    /// held down for push to talk
+    pub fn set_talking(&mut self, talking: bool) {
+        self.talking = talking;
+    }
+
+    pub fn gate(&mut self) -> &mut CaptureGate {
+        &mut self.gate
+    }
+
+    pub fn denoiser(&mut self) -> &mut Denoiser {
+        &mut self.denoiser
+    }
+
+    pub fn agc(&mut self) -> &mut Option<Agc> {
+        &mut self.gate.agc
+    }
+
+    /// `None` while echo cancellation is off
+    pub fn echo_canceller(&mut self) -> &mut Option<EchoCanceller> {
+        &mut self.echo_canceller
+    }
+
+    pub fn speaker_volume(&self, uid: i64) -> SpeakerVolume {
+        self.volumes.get(uid)
+    }
+
+    /// applied to the speaker's audio from the next frame on
+    pub fn set_speaker_volume(&mut self, uid: i64, volume: SpeakerVolume) {
+        if let Err(err) = self.volumes.set(uid, volume) {
+            log::error!("couldn't save the volume of {uid}: {err}");
+        }
+    }
+
+    /// the uids of everyone heard in the last `TALKING_HOLD`
+    pub fn talking_players(&self) -> Vec<i64> {
+        let mut uids = self
+            .voice
+            .speaking
+            .iter()
+            .filter(|(_, last_heard)| last_heard.elapsed() < TALKING_HOLD)
+            .map(|(uid, _)| *uid)
+            .collect::<Vec<i64>>();
+        uids.sort_unstable();
+        uids
+    }
+
+    /// the oldest message the player hasn't seen yet
+    pub fn pop_message(&mut self) -> Option<String> {
+        self.messages.pop_front()
+    }
+
+    fn push_message(&mut self, message: String) {
+        if self.messages.len() >= MAX_MESSAGES {
+            self.messages.pop_front();
+        }
+        self.messages.push_back(message);
+    }
+
+    /// drops the connection and forgets about the server
+    pub fn disconnect(&mut self) {
+        self.send_disconnect("left the game");
+        self.drop_stream();
+        self.server_host = None;
+        self.auth_token = None;
+    }
 
-        let input_stream = device
-            .build_input_stream(
-                &config,
-                move |data: &[AudioSampleType], _: &InputCallbackInfo| {
-                    _ = sender.send(data.to_vec());
-                },
-                |err| {
-                    log::error!("output stream error: {err}");
-                },
-                None,
-            )
-            .expect("failed to create a input stream");
-        self.input_stream = unsafe { Handle::new(input_stream) }.into();
+    /// lets the server drop the connection right away instead of waiting for it to time out
+    fn send_disconnect(&mut self, reason: &str) {
+        let Some(stream) = self.tcp_stream.as_mut() else {
+            return;
+        };
+
+        let disconnect = NetPacket::Disconnect {
+            reason: reason.to_string(),
+        };
+        if self.writer.queue(&disconnect).is_ok() {
+            _ = self.writer.flush(stream);
+        }
     }
 
     pub fn drop_stream(&mut self) {
         _ = self.tcp_stream.take();
+        _ = self.voice_socket.take();
         _ = self.ouput_stream.take();
         _ = self.input_stream.take();
-        self.auth_completed = false;
+        self.voice = VoiceState::default();
+        self.auth = AuthState::None;
+        self.heartbeat = Heartbeat::new(self.idle_timeout);
+        self.session = None;
+        self.features = Features::NONE;
         self.audio_buffer.clear();
-        self.read_buffer.clear();
+        self.reader.clear();
+        self.writer.clear();
     }
 
     pub fn run_thread(&mut self) {
         // assumptions : if TcpStream is Some then all other fields of Client are Some
 
-        if let Some(stream) = self.tcp_stream.as_mut() {
-            if let Ok(data) = self.recv_audio.try_recv() {
-                self.audio_buffer.extend(data);
+        if let (Some(stream), Some(voice_socket)) =
+            (self.tcp_stream.as_mut(), self.voice_socket.as_ref())
+        {
+            while let Ok(reference) = self.recv_reference.try_recv() {
+                if let Some(echo_canceller) = self.echo_canceller.as_mut() {
+                    echo_canceller.push_reference(&reference);
+                }
+            }
+
+            while let Ok(mut data) = self.recv_audio.try_recv() {
+                if let Some(echo_canceller) = self.echo_canceller.as_mut() {
+                    echo_canceller.process(&mut data);
+                }
+
+                self.denoiser.process(&data, &mut self.audio_buffer);
+            }
+
+            // it would all be sent in one burst once the auth completes
+            if self.session.is_none() {
+                self.audio_buffer.clear();
             }
 
             if let Err(err) = handle_sending(
                 stream,
-                &mut self.audio_buffer,
-                self.auth_completed,
+                &mut self.writer,
+                &mut self.auth,
+                &mut self.heartbeat,
+                self.auth_token,
                 self.uid,
             ) {
                 if !err.is_would_block_error() {
@@ -185,86 +454,304 @@ impl Client {
                 }
             }
 
-            let audio =
-                match handle_receiving(stream, &mut self.read_buffer, &mut self.auth_completed) {
-                    Err(err) if !err.is_would_block_error() => {
-                        log::error!("receiving: {err}");
-                        log::info!("terminating connection with server!");
-                        self.drop_stream();
-                        return;
+            match handle_receiving(
+                stream,
+                &mut self.reader,
+                &mut self.auth,
+                &mut self.heartbeat,
+                &mut self.session,
+                &mut self.features,
+                &mut self.voice,
+            ) {
+                Err(err) if !err.is_would_block_error() => {
+                    log::error!("receiving: {err}");
+                    log::info!("terminating connection with server!");
+                    match err {
+                        ProxiChatError::Rejected(reason) => self
+                            .push_message(format!("the server rejected the connection: {reason}")),
+                        ProxiChatError::Disconnected(reason) => {
+                            self.push_message(format!("the server disconnected you: {reason}"))
+                        }
+                        ProxiChatError::AuthTokenRejected => self.push_message(err.to_string()),
+                        _ => {}
                     }
-                    Ok(audio) => audio,
-                    _ => return,
-                };
+                    self.drop_stream();
+                    return;
+                }
+                Ok(_) => {}
+                _ => return,
+            };
+
+            if self.heartbeat.timed_out() {
+                log::error!("the server stopped responding");
+                log::info!("terminating connection with server!");
+                self.drop_stream();
+                return;
+            }
 
-            if let Some(audio) = audio {
-                if let Err(err) = self.send_audio.send(audio) {
-                    log::warn!("{err}");
+            let Some(session) = self.session else {
+                return;
+            };
+
+            let radio = self.radio && self.features.contains(Features::RADIO);
+            if let Err(err) = handle_sending_voice(
+                voice_socket,
+                &mut self.voice,
+                &mut self.audio_buffer,
+                session,
+                radio,
+                &mut self.gate,
+                self.talking || self.radio,
+            ) {
+                log::error!("sending voice: {err}");
+                log::info!("terminating connection with server!");
+                self.drop_stream();
+                return;
+            }
+
+            let audio = match handle_receiving_voice(voice_socket, &mut self.voice, &self.volumes) {
+                Ok(audio) => audio,
+                Err(err) => {
+                    log::error!("receiving voice: {err}");
+                    log::info!("terminating connection with server!");
+                    self.drop_stream();
+                    return;
                 }
+            };
+
+            let mut playback = self.playback.lock();
+            for (sequence, audio) in audio {
+                playback.push(sequence, audio);
             }
         }
     }
 }
 
-fn handle_sending(
+pub(crate) fn handle_sending(
     stream: &mut TcpStream,
-    audio_buffer: &mut AudioSampleVec,
-    auth_completed: bool,
+    writer: &mut PacketWriter,
+    auth: &mut AuthState,
+    heartbeat: &mut Heartbeat,
+    auth_token: Option<AuthToken>,
     uid: i64,
 ) -> Result<(), ProxiChatError> {
-    let packet: NetPacket = if auth_completed {
-        let mut buf = audio_buffer
-            .drain(0..AUDIO_BUFFER_SIZE)
-            .collect::<AudioSampleVec>();
-        buf.resize(AUDIO_BUFFER_SIZE, DEFAULT_FILL_SAMPLE);
-
-        NetPacket::NewAudio(
-            buf.try_into()
-                .map_err(|_| ProxiChatError::VecToArrayError)?,
-        )
-    } else {
-        NetPacket::Auth { uid }
-    };
-
-    let buf = bincode::serialize(&packet)?; // not very effective -> new alloc each time for nothing
+    match (std::mem::take(auth), auth_token) {
+        (AuthState::None, Some(token)) => {
+            writer.queue(&NetPacket::Version {
+                version: PROTOCOL_VERSION,
+            })?;
+
+            let handshake = Handshake::default();
+            writer.queue(&NetPacket::KeyExchange {
+                uid,
+                public_key: handshake.public_key(),
+            })?;
+            *auth = AuthState::KeyExchangeSent(handshake, token);
+        }
+        (AuthState::KeyExchanged(key), _) => {
+            writer.set_key(key);
+            writer.queue(&NetPacket::Auth {
+                capabilities: Capabilities::default(),
+            })?;
+            *auth = AuthState::AuthSent;
+        }
+        (state, _) => *auth = state,
+    }
 
-    stream.write_all(&buf)?;
+    // the server decrypts everything after the key exchange, so a ping can't go out in the clear
+    if matches!(auth, AuthState::AuthSent) {
+        heartbeat.queue(writer)?;
+    }
 
-    Ok(())
+    writer.flush(stream)
 }
 
-fn handle_receiving(
+pub(crate) fn handle_receiving(
     stream: &mut TcpStream,
-    read_buffer: &mut Vec<u8>,
-    auth_completed: &mut bool,
-) -> Result<Option<AudioSampleVec>, ProxiChatError> {
-    read_buffer.clear();
-
-    let size = stream.read(read_buffer)?;
-    // let size = read_until_success(stream, read_buffer, &mut 10)?;
-    if size > READ_BUFFER_SIZE {
-        log::warn!("incorrect amount of bytes received! found {size} exepected {READ_BUFFER_SIZE}");
-        return Ok(None);
+    reader: &mut PacketReader,
+    auth: &mut AuthState,
+    heartbeat: &mut Heartbeat,
+    session: &mut Option<u64>,
+    features: &mut Features,
+    voice: &mut VoiceState,
+) -> Result<(), ProxiChatError> {
+    reader.fill(stream)?;
+
+    loop {
+        let packet = match reader.next_packet() {
+            Ok(Some(packet)) => packet,
+            Ok(None) => break,
+            // the server's reject is sealed with keys a client with the wrong token doesn't have
+            Err(ProxiChatError::DecryptionFailed) if session.is_none() => {
+                Err(ProxiChatError::AuthTokenRejected)?
+            }
+            Err(err) => Err(err)?,
+        };
+        heartbeat.received(&packet);
+
+        match packet {
+            NetPacket::KeyExchangeReply { public_key } => {
+                let AuthState::KeyExchangeSent(handshake, token) = std::mem::take(auth) else {
+                    Err(ProxiChatError::ImpossibleOnClient)?
+                };
+
+                let keys = handshake.finish(Side::Client, public_key, token)?;
+                reader.set_key(keys.stream_opening);
+                voice.sealing = Some(keys.voice_sealing);
+                voice.opening = Some(keys.voice_opening);
+                *auth = AuthState::KeyExchanged(keys.stream_sealing);
+            }
+            NetPacket::AuthComfirm {
+                session: new_session,
+                settings,
+            } => {
+                log::info!(
+                    "auth completed with server; using {:?} at {} bps, {} hz and {} sample frames",
+                    settings.codec,
+                    settings.bitrate,
+                    settings.sample_rate,
+                    settings.frame_size
+                );
+                *session = Some(new_session);
+                *features = settings.features;
+                voice.encoder = VoiceEncoder::new(settings.codec, settings.bitrate);
+                voice.codec = settings.codec;
+                voice.speakers.clear();
+            }
+            NetPacket::Reject { reason } => Err(ProxiChatError::Rejected(reason))?,
+            NetPacket::Disconnect { reason } => Err(ProxiChatError::Disconnected(reason))?,
+            NetPacket::Ping | NetPacket::Pong => {}
+            NetPacket::None => {}
+            _ => Err(ProxiChatError::ImpossibleOnClient)?,
+        }
     }
 
-    // let packet: NetPacket = bincode::deserialize(read_buffer)?;
-    let packet: NetPacket = match bincode::deserialize(read_buffer) {
-        Ok(p) => p,
-        Err(_) => return Ok(None), // ignore all bincode on decode since it can get garbage data by accident
+    Ok(())
+}
+
+fn handle_sending_voice(
+    socket: &UdpSocket,
+    voice: &mut VoiceState,
+    audio_buffer: &mut AudioSampleVec,
+    session: u64,
+    radio: bool,
+    gate: &mut CaptureGate,
+    key_held: bool,
+) -> Result<(), ProxiChatError> {
+    let Some(key) = voice.sealing.as_mut() else {
+        return Ok(());
     };
 
-    log::info!("packet : {packet:?}");
+    if voice
+        .last_hello
+        .map(|last_hello| last_hello.elapsed() >= VOICE_HELLO_INTERVAL)
+        .unwrap_or(true)
+    {
+        send_voice(
+            socket,
+            None,
+            session,
+            &VoicePacket::Hello,
+            key,
+            &mut voice.send_buffer,
+        )?;
+        voice.last_hello = Some(Instant::now());
+    }
 
-    let audio = match packet {
-        NetPacket::AuthComfirm => {
-            log::info!("auth completed with server");
-            *auth_completed = true;
-            None
+    while audio_buffer.len() >= AUDIO_BUFFER_SIZE {
+        let frame = &mut audio_buffer[..AUDIO_BUFFER_SIZE];
+        if !gate.process(frame, key_held) {
+            audio_buffer.drain(0..AUDIO_BUFFER_SIZE);
+            // the gap in sequences tells the server's jitter buffer how long the pause was
+            voice.sequence = voice.sequence.wrapping_add(1);
+            continue;
         }
-        NetPacket::None => None,
-        NetPacket::ProccessedAudio(audio) => Some(audio.to_vec()),
-        _ => Err(ProxiChatError::ImpossibleOnClient)?,
-    };
+
+        voice.encoder.encode(frame, &mut voice.encoded_buffer);
+        audio_buffer.drain(0..AUDIO_BUFFER_SIZE);
+
+        let packet = VoicePacket::NewAudio {
+            sequence: voice.sequence,
+            radio,
+            audio: std::mem::take(&mut voice.encoded_buffer),
+        };
+        voice.sequence = voice.sequence.wrapping_add(1);
+
+        send_voice(socket, None, session, &packet, key, &mut voice.send_buffer)?;
+    }
+
+    Ok(())
+}
+
+/// spatializes every speaker of a frame where the server placed them, at the volume the player set
+fn handle_receiving_voice(
+    socket: &UdpSocket,
+    voice: &mut VoiceState,
+    volumes: &SpeakerVolumes,
+) -> Result<Vec<(u32, AudioSampleVec)>, ProxiChatError> {
+    let mut audio = Vec::new();
+
+    while let Some((datagram, _)) = recv_voice(socket, &mut voice.read_buffer)? {
+        let Some(key) = voice.opening.as_mut() else {
+            continue;
+        };
+
+        let packet = match datagram.open(key) {
+            Ok(packet) => packet,
+            // a repeated or very late datagram
+            Err(ProxiChatError::ReplayedPacket(_)) => continue,
+            Err(err) => {
+                log::warn!("dropping a voice packet: {err}");
+                continue;
+            }
+        };
+
+        match packet {
+            VoicePacket::ProccessedAudio { sequence, speakers } => {
+                voice.mix.clear();
+                voice
+                    .mix
+                    .resize(AUDIO_BUFFER_SIZE * OUTPUT_CHANNELS, DEFAULT_FILL_SAMPLE);
+
+                for speaker in speakers {
+                    voice.speaking.insert(speaker.uid, Instant::now());
+
+                    let gain = volumes.get(speaker.uid).gain() * speaker.placement.gain();
+                    if gain <= 0. {
+                        continue;
+                    }
+
+                    let playback = voice
+                        .speakers
+                        .entry(speaker.uid)
+                        .or_insert_with(|| SpeakerPlayback::new(voice.codec));
+                    if let Err(err) = playback.decode(sequence, &speaker.audio) {
+                        log::warn!("dropping voice frame of {}: {err}", speaker.uid);
+                        continue;
+                    }
+
+                    match speaker.placement.direction() {
+                        Some(direction) => spatialize(
+                            direction,
+                            gain,
+                            &playback.audio,
+                            &playback.previous_audio,
+                            &mut playback.filters,
+                            &mut voice.mix,
+                        ),
+                        None => add_centered(&playback.audio, gain, &mut voice.mix),
+                    }
+                }
+
+                // a few loud voices together would clip otherwise
+                voice.limiter.process(&mut voice.mix);
+
+                // late frames are dropped by the jitter buffer
+                audio.push((sequence, voice.mix.clone()));
+            }
+            _ => Err(ProxiChatError::ImpossibleOnClient)?,
+        }
+    }
 
     Ok(audio)
 }
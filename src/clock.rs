@@ -0,0 +1,87 @@
+use std::time::{Duration, Instant};
+
+use crate::shared::{AUDIO_BUFFER_SIZE, WIRE_SAMPLE_RATE};
+
+/// how far the clock catches up after a hitch, the rest is skipped instead of sent in one burst
+pub const MAX_CATCH_UP_FRAMES: usize = 32;
+
+/// counts how many audio frames are due since the last call from the wall clock
+///
+/// runframe ticks way slower than frames are played so every tick has to mix several
+#[derive(Debug, Default)]
+pub struct FrameClock {
+    start: Option<Instant>,
+    frames: u64,
+}
+
+impl FrameClock {
+    pub fn due(&mut self) -> usize {
+        self.due_at(Instant::now())
+    }
+
+    fn due_at(&mut self, now: Instant) -> usize {
+        let Some(start) = self.start else {
+            self.start = Some(now);
+            return 0;
+        };
+
+        let elapsed = frames_in(now.saturating_duration_since(start));
+        let due = elapsed.saturating_sub(self.frames);
+        self.frames = elapsed.max(self.frames);
+
+        due.min(MAX_CATCH_UP_FRAMES as u64) as usize
+    }
+}
+
+fn frames_in(duration: Duration) -> u64 {
+    (duration.as_nanos() * WIRE_SAMPLE_RATE as u128 / (AUDIO_BUFFER_SIZE as u128 * 1_000_000_000))
+        as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // rounded up so a multiple of it is never short of that many frames
+    const FRAME: Duration = Duration::from_nanos(
+        (AUDIO_BUFFER_SIZE as u64 * 1_000_000_000).div_ceil(WIRE_SAMPLE_RATE as u64),
+    );
+
+    #[test]
+    fn keeps_up_with_the_wall_clock() {
+        let start = Instant::now();
+        let mut clock = FrameClock::default();
+        assert_eq!(clock.due_at(start), 0);
+
+        // a 60 tick runframe
+        let total = (1..=60)
+            .map(|tick| clock.due_at(start + Duration::from_secs(tick) / 60))
+            .sum::<usize>();
+        assert_eq!(total, (WIRE_SAMPLE_RATE as usize) / AUDIO_BUFFER_SIZE);
+    }
+
+    #[test]
+    fn nothing_is_due_twice() {
+        let start = Instant::now();
+        let mut clock = FrameClock::default();
+        clock.due_at(start);
+
+        assert_eq!(clock.due_at(start + FRAME * 3), 3);
+        assert_eq!(clock.due_at(start + FRAME * 3), 0);
+        assert_eq!(clock.due_at(start + FRAME * 2), 0);
+        assert_eq!(clock.due_at(start + FRAME * 4), 1);
+    }
+
+    #[test]
+    fn skips_ahead_after_a_hitch() {
+        let start = Instant::now();
+        let mut clock = FrameClock::default();
+        clock.due_at(start);
+
+        assert_eq!(
+            clock.due_at(start + Duration::from_secs(1)),
+            MAX_CATCH_UP_FRAMES
+        );
+        assert_eq!(clock.due_at(start + Duration::from_secs(1) + FRAME), 1);
+    }
+}
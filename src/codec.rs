@@ -0,0 +1,545 @@
+use serde::{Deserialize, Serialize};
+use std::{ops::RangeInclusive, str::FromStr};
+
+use crate::{
+    resample::{Decimator, Interpolator},
+    shared::{
+        AudioSampleType, AudioSampleVec, ProxiChatError, AUDIO_BUFFER_SIZE, DEFAULT_FILL_SAMPLE,
+        WIRE_SAMPLE_RATE,
+    },
+};
+
+/// codecs this build can encode and decode, ordered by preference
+pub const SUPPORTED_CODECS: [Codec; 3] = [Codec::LowRateAdpcm, Codec::Adpcm, Codec::Pcm16];
+
+const FRAMES_PER_SECOND: u32 = WIRE_SAMPLE_RATE / AUDIO_BUFFER_SIZE as u32;
+
+const ADPCM_HEADER_SIZE: usize = 3;
+
+/// the low rate codec runs at a quarter of the wire rate, 12 khz keeps everything up to 5.4 khz
+const LOW_RATE_FACTOR: usize = 4;
+const LOW_RATE_FRAME_SIZE: usize = AUDIO_BUFFER_SIZE / LOW_RATE_FACTOR;
+/// bits per sample the low rate codec can use, each one is 12 kbps
+const LOW_RATE_BITS: RangeInclusive<usize> = 2..=5;
+
+/// how the step index moves for each magnitude of an adpcm code, by bits per code
+const LOW_RATE_INDEX_TABLES: [&[i8]; 4] = [
+    &[-1, 2],
+    &[-1, -1, 1, 2],
+    &[-1, -1, -1, -1, 2, 4, 6, 8],
+    &[-1, -1, -1, -1, -1, -1, -1, -1, 1, 2, 4, 6, 8, 10, 13, 16],
+];
+
+const ADPCM_INDEX_TABLE: [i8; 16] = [-1, -1, -1, -1, 2, 4, 6, 8, -1, -1, -1, -1, 2, 4, 6, 8];
+
+const ADPCM_STEP_TABLE: [i32; 89] = [
+    7, 8, 9, 10, 11, 12, 13, 14, 16, 17, 19, 21, 23, 25, 28, 31, 34, 37, 41, 45, 50, 55, 60, 66,
+    73, 80, 88, 97, 107, 118, 130, 143, 157, 173, 190, 209, 230, 253, 279, 307, 337, 371, 408, 449,
+    494, 544, 598, 658, 724, 796, 876, 963, 1060, 1166, 1282, 1411, 1552, 1707, 1878, 2066, 2272,
+    2499, 2749, 3024, 3327, 3660, 4026, 4428, 4871, 5358, 5894, 6484, 7132, 7845, 8630, 9493,
+    10442, 11487, 12635, 13899, 15289, 16818, 18500, 20350, 22385, 24623, 27086, 29794, 32767,
+];
+
+/// how voice frames are compressed on the wire
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum Codec {
+    /// 16 bit pcm, 2 bytes per sample
+    Pcm16,
+    /// ima adpcm, half a byte per sample and a 3 byte header per frame
+    Adpcm,
+    /// adpcm at 12 khz with 2 to 5 bits per sample, 33 to 69 kbps
+    LowRateAdpcm,
+}
+
+impl Codec {
+    /// the bits per second a stream of this codec can run at, headers included
+    pub fn bitrates(self) -> Vec<u32> {
+        match self {
+            Codec::Pcm16 => vec![frame_bitrate(AUDIO_BUFFER_SIZE * 2)],
+            Codec::Adpcm => vec![frame_bitrate(ADPCM_HEADER_SIZE + AUDIO_BUFFER_SIZE / 2)],
+            Codec::LowRateAdpcm => LOW_RATE_BITS
+                .map(|bits| frame_bitrate(low_rate_frame_size(bits)))
+                .collect(),
+        }
+    }
+
+    /// the highest bitrate that isn't over `target` or the lowest one if they all are
+    pub fn bitrate_for(self, target: u32) -> u32 {
+        let bitrates = self.bitrates();
+
+        bitrates
+            .iter()
+            .copied()
+            .filter(|bitrate| *bitrate <= target)
+            .max()
+            .or_else(|| bitrates.iter().copied().min())
+            .unwrap_or_default()
+    }
+
+    /// picks the first codec of `preferred` that the peer also supports
+    pub fn negotiate(preferred: &[Codec], supported: &[Codec]) -> Option<Codec> {
+        preferred
+            .iter()
+            .copied()
+            .find(|codec| supported.contains(codec))
+    }
+}
+
+impl FromStr for Codec {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "pcm16" => Ok(Codec::Pcm16),
+            "adpcm" => Ok(Codec::Adpcm),
+            "low_rate_adpcm" => Ok(Codec::LowRateAdpcm),
+            _ => Err(format!("unknown codec {s}")),
+        }
+    }
+}
+
+/// encodes one frame at a time, the adpcm step is carried over between frames
+#[derive(Debug)]
+pub struct VoiceEncoder {
+    codec: Codec,
+    adpcm_index: u8,
+    /// bits per sample of the low rate codec
+    low_rate_bits: usize,
+    decimator: Decimator,
+    low_rate_buffer: AudioSampleVec,
+}
+
+impl VoiceEncoder {
+    /// `bitrate` is rounded down to one the codec can do
+    pub fn new(codec: Codec, bitrate: u32) -> Self {
+        let bitrate = codec.bitrate_for(bitrate);
+
+        Self {
+            codec,
+            adpcm_index: 0,
+            low_rate_bits: LOW_RATE_BITS
+                .rev()
+                .find(|bits| frame_bitrate(low_rate_frame_size(*bits)) <= bitrate)
+                .unwrap_or(*LOW_RATE_BITS.start()),
+            decimator: Decimator::new(LOW_RATE_FACTOR),
+            low_rate_buffer: AudioSampleVec::with_capacity(LOW_RATE_FRAME_SIZE),
+        }
+    }
+
+    pub fn encode(&mut self, audio: &[AudioSampleType], out: &mut Vec<u8>) {
+        out.clear();
+
+        match self.codec {
+            Codec::Pcm16 => audio
+                .iter()
+                .for_each(|sample| out.extend_from_slice(&to_i16(*sample).to_le_bytes())),
+            Codec::Adpcm => self.encode_adpcm(audio, out),
+            Codec::LowRateAdpcm => {
+                self.low_rate_buffer.clear();
+                self.decimator.process(audio, &mut self.low_rate_buffer);
+                self.low_rate_buffer
+                    .resize(LOW_RATE_FRAME_SIZE, DEFAULT_FILL_SAMPLE);
+
+                self.adpcm_index = encode_low_rate_adpcm(
+                    &self.low_rate_buffer,
+                    self.low_rate_bits,
+                    self.adpcm_index,
+                    out,
+                );
+            }
+        }
+    }
+
+    fn encode_adpcm(&mut self, audio: &[AudioSampleType], out: &mut Vec<u8>) {
+        // every frame carries its starting state so a lost frame doesn't break the next ones
+        let mut predictor = audio.first().copied().map(to_i16).unwrap_or_default() as i32;
+        let mut index = self.adpcm_index as i32;
+
+        out.extend_from_slice(&(predictor as i16).to_le_bytes());
+        out.push(index as u8);
+
+        let mut nibbles = audio.iter().map(|sample| {
+            let step = ADPCM_STEP_TABLE[index as usize];
+            let mut diff = to_i16(*sample) as i32 - predictor;
+
+            let mut nibble = 0;
+            if diff < 0 {
+                nibble = 8;
+                diff = -diff;
+            }
+
+            let mut delta = step >> 3;
+            if diff >= step {
+                nibble |= 4;
+                diff -= step;
+                delta += step;
+            }
+            if diff >= step >> 1 {
+                nibble |= 2;
+                diff -= step >> 1;
+                delta += step >> 1;
+            }
+            if diff >= step >> 2 {
+                nibble |= 1;
+                delta += step >> 2;
+            }
+
+            predictor = if nibble & 8 != 0 {
+                predictor - delta
+            } else {
+                predictor + delta
+            }
+            .clamp(i16::MIN as i32, i16::MAX as i32);
+            index = (index + ADPCM_INDEX_TABLE[nibble as usize] as i32)
+                .clamp(0, ADPCM_STEP_TABLE.len() as i32 - 1);
+
+            nibble as u8
+        });
+
+        while let Some(low) = nibbles.next() {
+            let high = nibbles.next().unwrap_or_default();
+            out.push(low | high << 4);
+        }
+
+        self.adpcm_index = index as u8;
+    }
+}
+
+/// decodes frames produced by a `VoiceEncoder` with the same codec, at any of its bitrates
+#[derive(Debug)]
+pub struct VoiceDecoder {
+    codec: Codec,
+    interpolator: Interpolator,
+    low_rate_buffer: AudioSampleVec,
+}
+
+impl VoiceDecoder {
+    pub fn new(codec: Codec) -> Self {
+        Self {
+            codec,
+            interpolator: Interpolator::new(LOW_RATE_FACTOR),
+            low_rate_buffer: AudioSampleVec::with_capacity(LOW_RATE_FRAME_SIZE),
+        }
+    }
+
+    pub fn decode(&mut self, data: &[u8], out: &mut AudioSampleVec) -> Result<(), ProxiChatError> {
+        out.clear();
+
+        match self.codec {
+            Codec::Pcm16 => {
+                if data.len() != AUDIO_BUFFER_SIZE * 2 {
+                    Err(ProxiChatError::InvalidVoiceFrame(data.len()))?
+                }
+
+                out.extend(
+                    data.chunks_exact(2)
+                        .map(|bytes| from_i16(i16::from_le_bytes([bytes[0], bytes[1]]))),
+                );
+            }
+            Codec::Adpcm => decode_adpcm(data, out)?,
+            Codec::LowRateAdpcm => {
+                self.low_rate_buffer.clear();
+                decode_low_rate_adpcm(data, &mut self.low_rate_buffer)?;
+                self.interpolator.process(&self.low_rate_buffer, out);
+            }
+        }
+
+        Ok(())
+    }
+}
+
+fn decode_adpcm(data: &[u8], out: &mut AudioSampleVec) -> Result<(), ProxiChatError> {
+    if data.len() != ADPCM_HEADER_SIZE + AUDIO_BUFFER_SIZE / 2
+        || data[2] as usize >= ADPCM_STEP_TABLE.len()
+    {
+        Err(ProxiChatError::InvalidVoiceFrame(data.len()))?
+    }
+
+    let mut predictor = i16::from_le_bytes([data[0], data[1]]) as i32;
+    let mut index = data[2] as i32;
+
+    let nibbles = data[ADPCM_HEADER_SIZE..]
+        .iter()
+        .flat_map(|byte| [byte & 0xF, byte >> 4]);
+
+    for nibble in nibbles {
+        let step = ADPCM_STEP_TABLE[index as usize];
+
+        let mut delta = step >> 3;
+        if nibble & 4 != 0 {
+            delta += step;
+        }
+        if nibble & 2 != 0 {
+            delta += step >> 1;
+        }
+        if nibble & 1 != 0 {
+            delta += step >> 2;
+        }
+
+        predictor = if nibble & 8 != 0 {
+            predictor - delta
+        } else {
+            predictor + delta
+        }
+        .clamp(i16::MIN as i32, i16::MAX as i32);
+        index = (index + ADPCM_INDEX_TABLE[nibble as usize] as i32)
+            .clamp(0, ADPCM_STEP_TABLE.len() as i32 - 1);
+
+        out.push(from_i16(predictor as i16));
+    }
+
+    Ok(())
+}
+
+/// the frame starts with the first sample and the step index like ima adpcm,
+/// then `bits` wide codes of a sign and a magnitude follow packed from the lowest bit
+fn encode_low_rate_adpcm(
+    audio: &[AudioSampleType],
+    bits: usize,
+    index: u8,
+    out: &mut Vec<u8>,
+) -> u8 {
+    let mut predictor = audio.first().copied().map(to_i16).unwrap_or_default() as i32;
+    let mut index = index as i32;
+
+    out.extend_from_slice(&(predictor as i16).to_le_bytes());
+    out.push(index as u8);
+
+    let max_magnitude = (1 << (bits - 1)) - 1;
+    let mut packed = 0u32;
+    let mut packed_bits = 0;
+
+    for sample in audio {
+        let step = ADPCM_STEP_TABLE[index as usize];
+        let diff = to_i16(*sample) as i32 - predictor;
+
+        let magnitude = ((diff.abs() << (bits - 2)) / step).min(max_magnitude);
+        let sign = (diff < 0) as i32;
+
+        (predictor, index) = apply_low_rate_code(predictor, index, bits, sign, magnitude);
+
+        packed |= ((sign << (bits - 1) | magnitude) as u32) << packed_bits;
+        packed_bits += bits;
+        while packed_bits >= 8 {
+            out.push(packed as u8);
+            packed >>= 8;
+            packed_bits -= 8;
+        }
+    }
+
+    if packed_bits > 0 {
+        out.push(packed as u8);
+    }
+
+    index as u8
+}
+
+fn decode_low_rate_adpcm(data: &[u8], out: &mut AudioSampleVec) -> Result<(), ProxiChatError> {
+    // the bits per sample follow from the frame's size
+    let Some(bits) = LOW_RATE_BITS
+        .clone()
+        .find(|bits| low_rate_frame_size(*bits) == data.len())
+    else {
+        Err(ProxiChatError::InvalidVoiceFrame(data.len()))?
+    };
+    if data[2] as usize >= ADPCM_STEP_TABLE.len() {
+        Err(ProxiChatError::InvalidVoiceFrame(data.len()))?
+    }
+
+    let mut predictor = i16::from_le_bytes([data[0], data[1]]) as i32;
+    let mut index = data[2] as i32;
+
+    let mask = (1 << bits) - 1;
+    let mut packed = 0u32;
+    let mut packed_bits = 0;
+    let mut bytes = data[ADPCM_HEADER_SIZE..].iter();
+
+    for _ in 0..LOW_RATE_FRAME_SIZE {
+        while packed_bits < bits {
+            packed |= (*bytes.next().unwrap_or(&0) as u32) << packed_bits;
+            packed_bits += 8;
+        }
+
+        let code = (packed & mask) as i32;
+        packed >>= bits;
+        packed_bits -= bits;
+
+        (predictor, index) = apply_low_rate_code(
+            predictor,
+            index,
+            bits,
+            code >> (bits - 1),
+            code & ((1 << (bits - 1)) - 1),
+        );
+        out.push(from_i16(predictor as i16));
+    }
+
+    Ok(())
+}
+
+/// steps the predictor and the step index like the decoder will, returns both
+fn apply_low_rate_code(
+    predictor: i32,
+    index: i32,
+    bits: usize,
+    sign: i32,
+    magnitude: i32,
+) -> (i32, i32) {
+    let step = ADPCM_STEP_TABLE[index as usize];
+    let delta = (step * (2 * magnitude + 1)) >> (bits - 1);
+
+    let predictor = if sign != 0 {
+        predictor - delta
+    } else {
+        predictor + delta
+    }
+    .clamp(i16::MIN as i32, i16::MAX as i32);
+
+    let index = (index
+        + LOW_RATE_INDEX_TABLES[bits - *LOW_RATE_BITS.start()][magnitude as usize] as i32)
+        .clamp(0, ADPCM_STEP_TABLE.len() as i32 - 1);
+
+    (predictor, index)
+}
+
+fn low_rate_frame_size(bits: usize) -> usize {
+    ADPCM_HEADER_SIZE + (LOW_RATE_FRAME_SIZE * bits).div_ceil(8)
+}
+
+fn frame_bitrate(frame_size: usize) -> u32 {
+    frame_size as u32 * 8 * FRAMES_PER_SECOND
+}
+
+fn to_i16(sample: AudioSampleType) -> i16 {
+    (sample.clamp(-1., 1.) * i16::MAX as AudioSampleType) as i16
+}
+
+fn from_i16(sample: i16) -> AudioSampleType {
+    sample as AudioSampleType / i16::MAX as AudioSampleType
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::DEFAULT_BITRATE;
+
+    const FRAMES: usize = 40;
+
+    /// a few harmonics inside the voice band
+    fn voice(len: usize) -> AudioSampleVec {
+        (0..len)
+            .map(|i| {
+                let t = i as f32 / WIRE_SAMPLE_RATE as f32;
+                [(220., 0.3), (660., 0.15), (1800., 0.05)]
+                    .iter()
+                    .map(|(frequency, amplitude)| {
+                        amplitude * (2. * std::f32::consts::PI * frequency * t).sin()
+                    })
+                    .sum()
+            })
+            .collect()
+    }
+
+    /// encodes and decodes `audio` frame by frame and returns the decoded audio
+    fn round_trip(codec: Codec, bitrate: u32, audio: &[AudioSampleType]) -> AudioSampleVec {
+        let mut encoder = VoiceEncoder::new(codec, bitrate);
+        let mut decoder = VoiceDecoder::new(codec);
+        let mut encoded = Vec::new();
+        let mut frame = AudioSampleVec::new();
+        let mut decoded = AudioSampleVec::new();
+
+        for chunk in audio.chunks_exact(AUDIO_BUFFER_SIZE) {
+            encoder.encode(chunk, &mut encoded);
+            assert_eq!(
+                frame_bitrate(encoded.len()),
+                codec.bitrate_for(bitrate),
+                "{codec:?} at {bitrate}"
+            );
+
+            decoder.decode(&encoded, &mut frame).unwrap();
+            assert_eq!(frame.len(), AUDIO_BUFFER_SIZE);
+            decoded.extend_from_slice(&frame);
+        }
+
+        decoded
+    }
+
+    /// in db, the best of a few delays since the low rate codec's filters delay the audio
+    fn snr(original: &[AudioSampleType], decoded: &[AudioSampleType]) -> f32 {
+        // the first frames are the filters and the adpcm step settling
+        let start = AUDIO_BUFFER_SIZE * 4;
+
+        (0..64)
+            .map(|delay| {
+                let (signal, noise) = original[start..original.len() - delay]
+                    .iter()
+                    .zip(&decoded[start + delay..])
+                    .fold((0., 0.), |(signal, noise), (original, decoded)| {
+                        (
+                            signal + original * original,
+                            noise + (original - decoded).powi(2),
+                        )
+                    });
+
+                10. * (signal / noise.max(f32::EPSILON)).log10()
+            })
+            .fold(f32::MIN, f32::max)
+    }
+
+    #[test]
+    fn round_trips_keep_the_voice() {
+        let audio = voice(AUDIO_BUFFER_SIZE * FRAMES);
+
+        for (codec, bitrate, min_snr) in [
+            (Codec::Pcm16, u32::MAX, 70.),
+            (Codec::Adpcm, u32::MAX, 35.),
+            (Codec::LowRateAdpcm, u32::MAX, 30.),
+            (Codec::LowRateAdpcm, 57_000, 25.),
+            (Codec::LowRateAdpcm, 45_000, 15.),
+            (Codec::LowRateAdpcm, 0, 12.),
+        ] {
+            let snr = snr(&audio, &round_trip(codec, bitrate, &audio));
+            assert!(snr >= min_snr, "{codec:?} at {bitrate} bps: {snr} db");
+        }
+    }
+
+    #[test]
+    fn silence_stays_silent() {
+        let audio = vec![DEFAULT_FILL_SAMPLE; AUDIO_BUFFER_SIZE * FRAMES];
+
+        for codec in SUPPORTED_CODECS {
+            let decoded = round_trip(codec, DEFAULT_BITRATE, &audio);
+            assert!(
+                decoded.iter().all(|sample| sample.abs() < 0.01),
+                "{codec:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn bitrate_rounds_down() {
+        assert_eq!(Codec::LowRateAdpcm.bitrate_for(50_000), 45_000);
+        assert_eq!(Codec::LowRateAdpcm.bitrate_for(45_000), 45_000);
+        // nothing fits so the lowest it can do
+        assert_eq!(Codec::LowRateAdpcm.bitrate_for(1_000), 33_000);
+        assert_eq!(Codec::LowRateAdpcm.bitrate_for(u32::MAX), 69_000);
+        assert_eq!(Codec::Pcm16.bitrate_for(0), 768_000);
+    }
+
+    #[test]
+    fn broken_frames_are_refused() {
+        let mut audio = AudioSampleVec::new();
+
+        for codec in SUPPORTED_CODECS {
+            let mut decoder = VoiceDecoder::new(codec);
+            assert!(decoder.decode(&[0; 5], &mut audio).is_err(), "{codec:?}");
+        }
+
+        // a step index past the table
+        let mut frame = vec![0; low_rate_frame_size(4)];
+        frame[2] = ADPCM_STEP_TABLE.len() as u8;
+        assert!(VoiceDecoder::new(Codec::LowRateAdpcm)
+            .decode(&frame, &mut audio)
+            .is_err());
+    }
+}
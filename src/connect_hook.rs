@@ -1,9 +1,14 @@
 use once_cell::sync::OnceCell;
 use rrplug::{
-    bindings::command::CCommand, mid::concommands::find_concommand, prelude::CCommandResult,
+    bindings::command::CCommand,
+    mid::concommands::find_concommand,
+    prelude::{CCommandResult, EngineData},
 };
 
-use crate::{exports::PLUGIN, shared::PROXICHAT_PORT};
+use crate::{
+    denoise::Denoiser, dynamics::Agc, echo::EchoCanceller, exports::PLUGIN, gate::CaptureMode,
+    volumes::SpeakerVolume,
+};
 
 static ORIGINAL_CONNECT_FUNC: OnceCell<unsafe extern "C" fn(*const CCommand)> = OnceCell::new();
 static ORIGINAL_DISCONNECT_FUNC: OnceCell<unsafe extern "C" fn(*const CCommand)> = OnceCell::new();
@@ -38,34 +43,441 @@ pub fn setup_connect_hook() {
 unsafe extern "C" fn connect_hook(ccommand: *const CCommand) {
     let parsed_ccommand = CCommandResult::new(ccommand);
 
-    let ip = match parsed_ccommand.get_args().get(0) {
-        Some(arg) => arg.split(':').collect::<Vec<&str>>()[0],
+    let host = match parsed_ccommand.get_args().get(0) {
+        Some(arg) => server_host(arg),
         None => return log::error!("connect didn't have any args"),
     };
 
-    if ip == "localhost" {
+    if host == "localhost" {
         log::info!("found connection to local server; doing nothing");
         (ORIGINAL_CONNECT_FUNC.wait())(ccommand);
         return;
     }
 
     if let crate::shared::ProximityChatType::Client(client) = &PLUGIN.wait().proximity_chat {
-        log::info!("found connection to {ip}");
+        log::info!("found connection to {host}");
 
-        client
-            .lock()
-            .set_new_connection(format!("{ip}:{PROXICHAT_PORT}"));
+        client.lock().set_new_server(host.to_string());
     }
 
     (ORIGINAL_CONNECT_FUNC.wait())(ccommand);
 }
 
+/// strips the port from `ip:port`, `[ipv6]:port` or `host:port`, a bare ipv6 is kept whole
+fn server_host(addr: &str) -> &str {
+    if let Some(host) = addr
+        .strip_prefix('[')
+        .and_then(|addr| addr.split_once(']'))
+        .map(|(host, _)| host)
+    {
+        return host;
+    }
+
+    match addr.rsplit_once(':') {
+        Some((host, _)) if !host.contains(':') => host,
+        _ => addr,
+    }
+}
+
 unsafe extern "C" fn disconnect_hook(ccommand: *const CCommand) {
     if let crate::shared::ProximityChatType::Client(client) = &PLUGIN.wait().proximity_chat {
-        client.lock().drop_stream();
+        client.lock().disconnect();
 
         log::info!("found disconnect");
     }
 
     (ORIGINAL_DISCONNECT_FUNC.wait())(ccommand);
 }
+
+pub fn register_client_concommands(engine: &EngineData) {
+    // bind a key to +proxichat_radio to talk to the whole team while it's held
+    let key_commands: [(&str, unsafe extern "C" fn(*const CCommand), &str); 4] = [
+        (
+            "+proxichat_radio",
+            radio_down_command,
+            "talk to the whole team over the radio",
+        ),
+        (
+            "-proxichat_radio",
+            radio_up_command,
+            "talk to the whole team over the radio",
+        ),
+        ("+proxichat_talk", talk_down_command, "push to talk"),
+        ("-proxichat_talk", talk_up_command, "push to talk"),
+    ];
+    for (name, callback, help) in key_commands {
+        if let Err(err) = engine.register_concommand(name, callback, help, 0) {
+            log::error!("couldn't register {name}: {err:?}");
+        }
+    }
+
+    let capture_commands: [(&str, unsafe extern "C" fn(*const CCommand), &str); 7] = [
+        (
+            "proxichat_capture_mode",
+            capture_mode_command,
+            "usage: proxichat_capture_mode <always_on|push_to_talk|voice_activity>",
+        ),
+        (
+            "proxichat_vad_threshold",
+            vad_threshold_command,
+            "usage: proxichat_vad_threshold <dBFS>, how loud the mic has to be for voice_activity",
+        ),
+        (
+            "proxichat_high_pass",
+            high_pass_command,
+            "usage: proxichat_high_pass <off|cutoff in hz>, cuts rumble from the mic",
+        ),
+        (
+            "proxichat_noise_gate",
+            noise_gate_command,
+            "usage: proxichat_noise_gate <off|threshold in dBFS>, silences the mic under the threshold",
+        ),
+        (
+            "proxichat_noise_suppression",
+            noise_suppression_command,
+            "usage: proxichat_noise_suppression <off|strength from 0 to 1>, removes steady background noise",
+        ),
+        (
+            "proxichat_agc",
+            agc_command,
+            "usage: proxichat_agc <off|target in dBFS>, evens out how loud the mic is",
+        ),
+        (
+            "proxichat_echo_cancellation",
+            echo_cancellation_command,
+            "usage: proxichat_echo_cancellation <0|1>, removes your speakers from the mic",
+        ),
+    ];
+    for (name, callback, help) in capture_commands {
+        if let Err(err) = engine.register_concommand(name, callback, help, 0) {
+            log::error!("couldn't register {name}: {err:?}");
+        }
+    }
+
+    let volume_commands: [(&str, unsafe extern "C" fn(*const CCommand), &str); 4] = [
+        (
+            "proxichat_volume",
+            volume_command,
+            "usage: proxichat_volume <uid> <0 to 2>, prints the volume without one",
+        ),
+        (
+            "proxichat_local_mute",
+            local_mute_command,
+            "usage: proxichat_local_mute <uid>, only you stop hearing them",
+        ),
+        (
+            "proxichat_local_unmute",
+            local_unmute_command,
+            "usage: proxichat_local_unmute <uid>",
+        ),
+        (
+            "proxichat_talking",
+            talking_command,
+            "prints who is talking and the volume you set for them",
+        ),
+    ];
+    for (name, callback, help) in volume_commands {
+        if let Err(err) = engine.register_concommand(name, callback, help, 0) {
+            log::error!("couldn't register {name}: {err:?}");
+        }
+    }
+}
+
+unsafe extern "C" fn radio_down_command(_: *const CCommand) {
+    if let crate::shared::ProximityChatType::Client(client) = &PLUGIN.wait().proximity_chat {
+        client.lock().set_radio(true);
+    }
+}
+
+unsafe extern "C" fn radio_up_command(_: *const CCommand) {
+    if let crate::shared::ProximityChatType::Client(client) = &PLUGIN.wait().proximity_chat {
+        client.lock().set_radio(false);
+    }
+}
+
+unsafe extern "C" fn talk_down_command(_: *const CCommand) {
+    if let crate::shared::ProximityChatType::Client(client) = &PLUGIN.wait().proximity_chat {
+        client.lock().set_talking(true);
+    }
+}
+
+unsafe extern "C" fn talk_up_command(_: *const CCommand) {
+    if let crate::shared::ProximityChatType::Client(client) = &PLUGIN.wait().proximity_chat {
+        client.lock().set_talking(false);
+    }
+}
+
+/// prints the current mode without args
+unsafe extern "C" fn capture_mode_command(ccommand: *const CCommand) {
+    let parsed_ccommand = CCommandResult::new(ccommand);
+
+    if let crate::shared::ProximityChatType::Client(client) = &PLUGIN.wait().proximity_chat {
+        let mut client = client.lock();
+
+        match parsed_ccommand
+            .get_args()
+            .first()
+            .map(|arg| arg.parse::<CaptureMode>())
+        {
+            Some(Ok(mode)) => {
+                client.gate().mode = mode;
+                log::info!("capture mode set to {mode}");
+            }
+            Some(Err(err)) => log::error!("{err}"),
+            None => log::info!("capture mode is {}", client.gate().mode),
+        }
+    }
+}
+
+/// prints the current threshold without args
+unsafe extern "C" fn vad_threshold_command(ccommand: *const CCommand) {
+    let parsed_ccommand = CCommandResult::new(ccommand);
+
+    if let crate::shared::ProximityChatType::Client(client) = &PLUGIN.wait().proximity_chat {
+        let mut client = client.lock();
+
+        match parsed_ccommand
+            .get_args()
+            .first()
+            .map(|arg| arg.parse::<f32>())
+        {
+            Some(Ok(threshold)) => {
+                client.gate().threshold = threshold.min(0.);
+                log::info!(
+                    "voice activity threshold set to {} dBFS",
+                    client.gate().threshold
+                );
+            }
+            Some(Err(err)) => log::error!("invalid threshold: {err}"),
+            None => log::info!(
+                "voice activity threshold is {} dBFS",
+                client.gate().threshold
+            ),
+        }
+    }
+}
+
+unsafe extern "C" fn high_pass_command(ccommand: *const CCommand) {
+    denoiser_command(ccommand, "high pass", |denoiser, cutoff| match cutoff {
+        Some(cutoff) => {
+            denoiser.set_high_pass(cutoff);
+            denoiser.high_pass()
+        }
+        None => denoiser.high_pass(),
+    })
+}
+
+unsafe extern "C" fn noise_gate_command(ccommand: *const CCommand) {
+    denoiser_command(
+        ccommand,
+        "noise gate",
+        |denoiser, threshold| match threshold {
+            Some(threshold) => {
+                denoiser.set_gate(threshold);
+                denoiser.gate()
+            }
+            None => denoiser.gate(),
+        },
+    )
+}
+
+unsafe extern "C" fn noise_suppression_command(ccommand: *const CCommand) {
+    denoiser_command(
+        ccommand,
+        "noise suppression",
+        |denoiser, strength| match strength {
+            Some(strength) => {
+                denoiser.set_suppression(strength);
+                denoiser.suppression()
+            }
+            None => denoiser.suppression(),
+        },
+    )
+}
+
+unsafe extern "C" fn agc_command(ccommand: *const CCommand) {
+    let parsed_ccommand = CCommandResult::new(ccommand);
+
+    let setting = match parsed_ccommand.get_args().first().map(|arg| arg.as_str()) {
+        Some("off") => Some(None),
+        Some(arg) => match arg.parse::<f32>() {
+            Ok(target) => Some(Some(target)),
+            Err(err) => return log::error!("invalid agc target {arg}: {err}"),
+        },
+        None => None,
+    };
+
+    if let crate::shared::ProximityChatType::Client(client) = &PLUGIN.wait().proximity_chat {
+        let mut client = client.lock();
+        let agc = client.agc();
+
+        match (setting, agc.as_mut()) {
+            (Some(Some(target)), Some(agc)) => agc.target = target.min(0.),
+            (Some(target), _) => *agc = target.map(Agc::new),
+            (None, _) => {}
+        }
+
+        match agc {
+            Some(agc) => log::info!("agc is on at {} dBFS", agc.target),
+            None => log::info!("agc is off"),
+        }
+    }
+}
+
+unsafe extern "C" fn echo_cancellation_command(ccommand: *const CCommand) {
+    let parsed_ccommand = CCommandResult::new(ccommand);
+
+    let enabled = match parsed_ccommand.get_args().first().map(|arg| arg.as_str()) {
+        Some("1" | "on") => Some(true),
+        Some("0" | "off") => Some(false),
+        Some(arg) => return log::error!("invalid echo cancellation setting {arg}"),
+        None => None,
+    };
+
+    if let crate::shared::ProximityChatType::Client(client) = &PLUGIN.wait().proximity_chat {
+        let mut client = client.lock();
+        let echo_canceller = client.echo_canceller();
+
+        match enabled {
+            Some(true) if echo_canceller.is_none() => {
+                *echo_canceller = Some(EchoCanceller::default())
+            }
+            Some(false) => *echo_canceller = None,
+            _ => {}
+        }
+
+        match echo_canceller {
+            Some(_) => log::info!("echo cancellation is on"),
+            None => log::info!("echo cancellation is off"),
+        }
+    }
+}
+
+/// `f` gets `None` without args, `Some(None)` for off and `Some(Some(value))` otherwise
+/// and returns the stage's setting afterwards
+fn denoiser_command(
+    ccommand: *const CCommand,
+    stage: &str,
+    f: impl FnOnce(&mut Denoiser, Option<Option<f32>>) -> Option<f32>,
+) {
+    let parsed_ccommand = CCommandResult::new(ccommand);
+
+    let setting = match parsed_ccommand.get_args().first().map(|arg| arg.as_str()) {
+        Some("off" | "0") => Some(None),
+        Some(arg) => match arg.parse::<f32>() {
+            Ok(value) => Some(Some(value)),
+            Err(err) => return log::error!("invalid {stage} setting {arg}: {err}"),
+        },
+        None => None,
+    };
+
+    if let crate::shared::ProximityChatType::Client(client) = &PLUGIN.wait().proximity_chat {
+        match f(client.lock().denoiser(), setting) {
+            Some(value) => log::info!("{stage} is on at {value}"),
+            None => log::info!("{stage} is off"),
+        }
+    }
+}
+
+unsafe extern "C" fn volume_command(ccommand: *const CCommand) {
+    let parsed_ccommand = CCommandResult::new(ccommand);
+    let args = parsed_ccommand.get_args();
+
+    let Some(uid) = parse_uid_arg(args.first()) else {
+        return;
+    };
+
+    if let crate::shared::ProximityChatType::Client(client) = &PLUGIN.wait().proximity_chat {
+        let mut client = client.lock();
+        let current = client.speaker_volume(uid);
+
+        match args.get(1).map(|arg| arg.parse::<f32>()) {
+            Some(Ok(volume)) => {
+                client.set_speaker_volume(uid, SpeakerVolume { volume, ..current });
+                log::info!(
+                    "volume of {uid} set to {}",
+                    client.speaker_volume(uid).volume
+                );
+            }
+            Some(Err(err)) => log::error!("invalid volume: {err}"),
+            None => log::info!("volume of {uid} is {}", current.volume),
+        }
+    }
+}
+
+unsafe extern "C" fn local_mute_command(ccommand: *const CCommand) {
+    set_local_mute(ccommand, true)
+}
+
+unsafe extern "C" fn local_unmute_command(ccommand: *const CCommand) {
+    set_local_mute(ccommand, false)
+}
+
+fn set_local_mute(ccommand: *const CCommand, muted: bool) {
+    let parsed_ccommand = CCommandResult::new(ccommand);
+
+    let Some(uid) = parse_uid_arg(parsed_ccommand.get_args().first()) else {
+        return;
+    };
+
+    if let crate::shared::ProximityChatType::Client(client) = &PLUGIN.wait().proximity_chat {
+        let mut client = client.lock();
+        let current = client.speaker_volume(uid);
+        client.set_speaker_volume(uid, SpeakerVolume { muted, ..current });
+
+        log::info!("{} {uid}", if muted { "muted" } else { "unmuted" });
+    }
+}
+
+unsafe extern "C" fn talking_command(_: *const CCommand) {
+    if let crate::shared::ProximityChatType::Client(client) = &PLUGIN.wait().proximity_chat {
+        let client = client.lock();
+        let talking = client.talking_players();
+
+        if talking.is_empty() {
+            return log::info!("nobody is talking");
+        }
+
+        for uid in talking {
+            let volume = client.speaker_volume(uid);
+            match volume.muted {
+                true => log::info!("{uid} at volume {} (muted)", volume.volume),
+                false => log::info!("{uid} at volume {}", volume.volume),
+            }
+        }
+    }
+}
+
+fn parse_uid_arg(arg: Option<&String>) -> Option<i64> {
+    match arg.map(|arg| arg.parse::<i64>()) {
+        Some(Ok(uid)) => Some(uid),
+        Some(Err(err)) => {
+            log::error!("invalid uid: {err}");
+            None
+        }
+        None => {
+            log::error!("a uid is needed");
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn strips_the_port() {
+        assert_eq!(server_host("192.168.1.5:37015"), "192.168.1.5");
+        assert_eq!(server_host("example.com:37015"), "example.com");
+        assert_eq!(server_host("[2001:db8::1]:37015"), "2001:db8::1");
+        assert_eq!(server_host("[::1]"), "::1");
+    }
+
+    #[test]
+    fn keeps_a_host_without_a_port() {
+        assert_eq!(server_host("192.168.1.5"), "192.168.1.5");
+        assert_eq!(server_host("localhost"), "localhost");
+        assert_eq!(server_host("2001:db8::1"), "2001:db8::1");
+    }
+}
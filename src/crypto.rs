@@ -0,0 +1,365 @@
+use chacha20poly1305::{aead::AeadInPlace, ChaCha20Poly1305, Key, KeyInit, Nonce};
+use hkdf::Hkdf;
+use sha2::Sha256;
+use x25519_dalek::{EphemeralSecret, PublicKey};
+
+use crate::{auth::AuthToken, shared::ProxiChatError};
+
+pub type PublicKeyBytes = [u8; 32];
+
+const KEY_SIZE: usize = 32;
+const KEY_INFO: &[u8] = b"r2proxi-chat session keys";
+/// how far behind the newest counter a datagram can still be opened
+const REPLAY_WINDOW: u64 = u64::BITS as u64;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Side {
+    Client,
+    Server,
+}
+
+/// one side of an x25519 key exchange, a new one is made for every connection
+pub struct Handshake {
+    secret: EphemeralSecret,
+    public_key: PublicKey,
+}
+
+impl Default for Handshake {
+    fn default() -> Self {
+        let secret = EphemeralSecret::random();
+        let public_key = PublicKey::from(&secret);
+
+        Self { secret, public_key }
+    }
+}
+
+impl std::fmt::Debug for Handshake {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Handshake")
+            .field("public_key", &self.public_key)
+            .finish()
+    }
+}
+
+impl Handshake {
+    pub fn public_key(&self) -> PublicKeyBytes {
+        self.public_key.to_bytes()
+    }
+
+    /// the auth token is mixed into the keys so only the player who got it in game ends up
+    /// with the same keys as the server, anyone else's packets just fail to decrypt
+    pub fn finish(
+        self,
+        side: Side,
+        their_public_key: PublicKeyBytes,
+        token: AuthToken,
+    ) -> Result<SessionKeys, ProxiChatError> {
+        let their_public_key = PublicKey::from(their_public_key);
+        let shared_secret = self.secret.diffie_hellman(&their_public_key);
+        if !shared_secret.was_contributory() {
+            Err(ProxiChatError::KeyExchangeFailed)?
+        }
+
+        let (client_public_key, server_public_key) = match side {
+            Side::Client => (self.public_key, their_public_key),
+            Side::Server => (their_public_key, self.public_key),
+        };
+        let info = [
+            KEY_INFO,
+            client_public_key.as_bytes(),
+            server_public_key.as_bytes(),
+        ]
+        .concat();
+
+        let mut keys = [0; KEY_SIZE * 4];
+        Hkdf::<Sha256>::new(Some(&token.to_bytes()), shared_secret.as_bytes())
+            .expand(&info, &mut keys)
+            .map_err(|_| ProxiChatError::KeyExchangeFailed)?;
+
+        let mut keys = keys.chunks_exact(KEY_SIZE);
+        let mut next_key = || keys.next().expect("four keys were derived");
+        let (client_stream, server_stream, client_voice, server_voice) =
+            (next_key(), next_key(), next_key(), next_key());
+
+        Ok(match side {
+            Side::Client => SessionKeys {
+                stream_sealing: SealingKey::new(client_stream),
+                stream_opening: OpeningKey::new(server_stream),
+                voice_sealing: SealingKey::new(client_voice),
+                voice_opening: OpeningKey::new(server_voice),
+            },
+            Side::Server => SessionKeys {
+                stream_sealing: SealingKey::new(server_stream),
+                stream_opening: OpeningKey::new(client_stream),
+                voice_sealing: SealingKey::new(server_voice),
+                voice_opening: OpeningKey::new(client_voice),
+            },
+        })
+    }
+}
+
+/// every direction of the tcp stream and of the voice socket gets its own key
+#[derive(Debug)]
+pub struct SessionKeys {
+    pub stream_sealing: SealingKey,
+    pub stream_opening: OpeningKey,
+    pub voice_sealing: SealingKey,
+    pub voice_opening: OpeningKey,
+}
+
+/// encrypts outgoing packets, the nonce is a counter so it's never reused
+pub struct SealingKey {
+    cipher: ChaCha20Poly1305,
+    counter: u64,
+}
+
+impl SealingKey {
+    fn new(key: &[u8]) -> Self {
+        Self {
+            cipher: ChaCha20Poly1305::new(Key::from_slice(key)),
+            counter: 0,
+        }
+    }
+
+    /// encrypts `buffer` in place and appends the tag, returns the counter it was sealed with
+    pub fn seal(&mut self, buffer: &mut Vec<u8>) -> Result<u64, ProxiChatError> {
+        let counter = self.counter;
+        self.counter = counter
+            .checked_add(1)
+            .ok_or(ProxiChatError::EncryptionFailed)?;
+
+        self.cipher
+            .encrypt_in_place(&nonce(counter), &[], buffer)
+            .map_err(|_| ProxiChatError::EncryptionFailed)?;
+
+        Ok(counter)
+    }
+}
+
+impl std::fmt::Debug for SealingKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SealingKey")
+            .field("counter", &self.counter)
+            .finish()
+    }
+}
+
+/// decrypts incoming packets and refuses any counter that was already used
+///
+/// datagrams can arrive out of order so the counters right behind the newest one
+/// are tracked in a sliding window like ipsec and dtls do
+pub struct OpeningKey {
+    cipher: ChaCha20Poly1305,
+    next_counter: u64,
+    /// bit `i` is set if `next_counter - 1 - i` was opened
+    window: u64,
+}
+
+impl OpeningKey {
+    fn new(key: &[u8]) -> Self {
+        Self {
+            cipher: ChaCha20Poly1305::new(Key::from_slice(key)),
+            next_counter: 0,
+            window: 0,
+        }
+    }
+
+    /// a counter that was already opened or is `REPLAY_WINDOW` behind the newest one is refused
+    pub fn open(&mut self, counter: u64, buffer: &mut Vec<u8>) -> Result<(), ProxiChatError> {
+        let age = (counter < self.next_counter).then(|| self.next_counter - 1 - counter);
+        if let Some(age) = age {
+            if age >= REPLAY_WINDOW || self.window & (1 << age) != 0 {
+                Err(ProxiChatError::ReplayedPacket(counter))?
+            }
+        }
+
+        self.cipher
+            .decrypt_in_place(&nonce(counter), &[], buffer)
+            .map_err(|_| ProxiChatError::DecryptionFailed)?;
+
+        match age {
+            Some(age) => self.window |= 1 << age,
+            None => {
+                let shift = counter - self.next_counter + 1;
+                self.window = if shift >= REPLAY_WINDOW {
+                    0
+                } else {
+                    self.window << shift
+                };
+                self.window |= 1;
+                self.next_counter = counter.saturating_add(1);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// for the tcp stream where packets can't arrive out of order
+    pub fn open_next(&mut self, buffer: &mut Vec<u8>) -> Result<(), ProxiChatError> {
+        self.open(self.next_counter, buffer)
+    }
+}
+
+impl std::fmt::Debug for OpeningKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("OpeningKey")
+            .field("next_counter", &self.next_counter)
+            .finish()
+    }
+}
+
+fn nonce(counter: u64) -> Nonce {
+    let mut nonce = Nonce::default();
+    nonce[4..].copy_from_slice(&counter.to_le_bytes());
+    nonce
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn exchange(client_token: AuthToken, server_token: AuthToken) -> (SessionKeys, SessionKeys) {
+        let client = Handshake::default();
+        let server = Handshake::default();
+        let (client_public_key, server_public_key) = (client.public_key(), server.public_key());
+
+        (
+            client
+                .finish(Side::Client, server_public_key, client_token)
+                .unwrap(),
+            server
+                .finish(Side::Server, client_public_key, server_token)
+                .unwrap(),
+        )
+    }
+
+    #[test]
+    fn both_sides_derive_the_same_keys() {
+        let token = AuthToken::generate().unwrap();
+        let (mut client, mut server) = exchange(token, token);
+
+        let mut buffer = b"client to server".to_vec();
+        client.stream_sealing.seal(&mut buffer).unwrap();
+        server.stream_opening.open_next(&mut buffer).unwrap();
+        assert_eq!(buffer, b"client to server");
+
+        let mut buffer = b"server to client".to_vec();
+        let counter = server.voice_sealing.seal(&mut buffer).unwrap();
+        client.voice_opening.open(counter, &mut buffer).unwrap();
+        assert_eq!(buffer, b"server to client");
+    }
+
+    #[test]
+    fn directions_use_different_keys() {
+        let token = AuthToken::generate().unwrap();
+        let (mut client, mut server) = exchange(token, token);
+
+        let mut buffer = b"voice".to_vec();
+        client.voice_sealing.seal(&mut buffer).unwrap();
+        assert!(matches!(
+            server.stream_opening.open_next(&mut buffer),
+            Err(ProxiChatError::DecryptionFailed)
+        ));
+    }
+
+    #[test]
+    fn wrong_token_fails_to_decrypt() {
+        let (mut client, mut server) = exchange(
+            AuthToken::generate().unwrap(),
+            AuthToken::generate().unwrap(),
+        );
+
+        let mut buffer = b"auth".to_vec();
+        client.stream_sealing.seal(&mut buffer).unwrap();
+        assert!(matches!(
+            server.stream_opening.open_next(&mut buffer),
+            Err(ProxiChatError::DecryptionFailed)
+        ));
+    }
+
+    #[test]
+    fn replays_are_refused() {
+        let token = AuthToken::generate().unwrap();
+        let (mut client, mut server) = exchange(token, token);
+
+        let mut first = b"first".to_vec();
+        let first_counter = client.voice_sealing.seal(&mut first).unwrap();
+        let mut second = b"second".to_vec();
+        let second_counter = client.voice_sealing.seal(&mut second).unwrap();
+
+        let mut replay = second.clone();
+        server
+            .voice_opening
+            .open(second_counter, &mut second)
+            .unwrap();
+
+        assert!(matches!(
+            server.voice_opening.open(second_counter, &mut replay),
+            Err(ProxiChatError::ReplayedPacket(counter)) if counter == second_counter
+        ));
+
+        let mut replay = first.clone();
+        server
+            .voice_opening
+            .open(first_counter, &mut first)
+            .unwrap();
+        assert!(matches!(
+            server.voice_opening.open(first_counter, &mut replay),
+            Err(ProxiChatError::ReplayedPacket(counter)) if counter == first_counter
+        ));
+    }
+
+    #[test]
+    fn opens_datagrams_out_of_order() {
+        let token = AuthToken::generate().unwrap();
+        let (mut client, mut server) = exchange(token, token);
+
+        let mut datagrams = (0..3)
+            .map(|i| {
+                let mut buffer = vec![i];
+                let counter = client.voice_sealing.seal(&mut buffer).unwrap();
+                (counter, buffer)
+            })
+            .collect::<Vec<_>>();
+        datagrams.swap(1, 2);
+
+        for (counter, mut buffer) in datagrams {
+            server.voice_opening.open(counter, &mut buffer).unwrap();
+            assert_eq!(buffer, [counter as u8]);
+        }
+    }
+
+    #[test]
+    fn refuses_datagrams_behind_the_window() {
+        let token = AuthToken::generate().unwrap();
+        let (mut client, mut server) = exchange(token, token);
+
+        let mut old = b"old".to_vec();
+        let old_counter = client.voice_sealing.seal(&mut old).unwrap();
+        for _ in 0..REPLAY_WINDOW {
+            client.voice_sealing.seal(&mut Vec::new()).unwrap();
+        }
+        let mut new = b"new".to_vec();
+        let new_counter = client.voice_sealing.seal(&mut new).unwrap();
+
+        server.voice_opening.open(new_counter, &mut new).unwrap();
+        assert!(matches!(
+            server.voice_opening.open(old_counter, &mut old),
+            Err(ProxiChatError::ReplayedPacket(_))
+        ));
+    }
+
+    #[test]
+    fn tampered_packets_fail_to_decrypt() {
+        let token = AuthToken::generate().unwrap();
+        let (mut client, mut server) = exchange(token, token);
+
+        let mut buffer = b"voice".to_vec();
+        let counter = client.voice_sealing.seal(&mut buffer).unwrap();
+        buffer[0] ^= 1;
+        assert!(matches!(
+            server.voice_opening.open(counter, &mut buffer),
+            Err(ProxiChatError::DecryptionFailed)
+        ));
+    }
+}
@@ -0,0 +1,370 @@
+use fundsp::hacker32::*;
+use std::f32::consts::PI;
+
+use crate::shared::{
+    time_coefficient, AudioSampleType, AudioSampleVec, AUDIO_BUFFER_SIZE, WIRE_SAMPLE_RATE,
+};
+
+const HIGH_PASS_Q: f32 = 0.7;
+/// how fast the gate opens and closes, in seconds
+const GATE_ATTACK: f32 = 0.001;
+const GATE_RELEASE: f32 = 0.1;
+/// how fast the gate's level detector falls, in seconds
+const GATE_ENVELOPE_RELEASE: f32 = 0.05;
+/// the suppressor works on windows of two frames that overlap by one frame
+const FFT_SIZE: usize = AUDIO_BUFFER_SIZE * 2;
+const HOP_SIZE: usize = AUDIO_BUFFER_SIZE;
+/// how much of the estimated noise is removed at full strength, over subtracting hides musical noise
+const OVERSUBTRACTION: f32 = 4.;
+/// a bin is never turned down more than this
+const SUPPRESSION_FLOOR: f32 = 0.1;
+/// how fast the noise estimate can rise when the noise gets louder, per hop
+const NOISE_RISE: f32 = 0.002;
+/// a bin this far above the noise estimate is taken as speech, the estimate only creeps towards it
+/// so a held vowel isn't mistaken for noise
+const SPEECH_RATIO: f32 = 10.;
+const SPEECH_NOISE_RISE: f32 = 0.00005;
+/// smoothing of the bin powers and gains between hops
+const SPECTRAL_SMOOTHING: f32 = 0.5;
+
+/// the optional microphone cleanup, every stage is off until it's configured
+///
+/// high pass -> spectral noise suppression -> noise gate
+#[derive(Default)]
+pub struct Denoiser {
+    high_pass: Option<(f32, Box<dyn AudioUnit32>)>,
+    gate: Option<NoiseGate>,
+    suppressor: Option<Box<SpectralSuppressor>>,
+    buffer: AudioSampleVec,
+}
+
+impl std::fmt::Debug for Denoiser {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Denoiser")
+            .field("high_pass", &self.high_pass())
+            .field("gate", &self.gate())
+            .field("suppression", &self.suppression())
+            .finish()
+    }
+}
+
+impl Denoiser {
+    /// the high pass cutoff in hz
+    pub fn high_pass(&self) -> Option<f32> {
+        self.high_pass.as_ref().map(|(cutoff, _)| *cutoff)
+    }
+
+    pub fn set_high_pass(&mut self, cutoff: Option<f32>) {
+        self.high_pass = cutoff.map(|cutoff| {
+            let cutoff = cutoff.clamp(20., 1000.);
+            let mut filter: Box<dyn AudioUnit32> = Box::new(highpass_hz(cutoff, HIGH_PASS_Q));
+            filter.reset(Some(WIRE_SAMPLE_RATE as f64));
+
+            (cutoff, filter)
+        });
+    }
+
+    /// the gate threshold in dBFS
+    pub fn gate(&self) -> Option<f32> {
+        self.gate.as_ref().map(|gate| gate.threshold)
+    }
+
+    pub fn set_gate(&mut self, threshold: Option<f32>) {
+        match (threshold, self.gate.as_mut()) {
+            (Some(threshold), Some(gate)) => gate.threshold = threshold.min(0.),
+            (threshold, _) => self.gate = threshold.map(NoiseGate::new),
+        }
+    }
+
+    /// how much of the noise is removed, from 0 to 1
+    pub fn suppression(&self) -> Option<f32> {
+        self.suppressor
+            .as_ref()
+            .map(|suppressor| suppressor.strength)
+    }
+
+    pub fn set_suppression(&mut self, strength: Option<f32>) {
+        match (strength, self.suppressor.as_mut()) {
+            (Some(strength), Some(suppressor)) => suppressor.strength = strength.clamp(0., 1.),
+            (strength, _) => {
+                self.suppressor =
+                    strength.map(|strength| Box::new(SpectralSuppressor::new(strength)))
+            }
+        }
+    }
+
+    /// appends the cleaned up `input` to `out`
+    ///
+    /// the suppressor holds back up to a frame until it has a full hop
+    pub fn process(&mut self, input: &[AudioSampleType], out: &mut AudioSampleVec) {
+        self.buffer.clear();
+        self.buffer.extend_from_slice(input);
+
+        if let Some((_, filter)) = self.high_pass.as_mut() {
+            for sample in self.buffer.iter_mut() {
+                let mut filtered = [0.];
+                filter.tick(&[*sample], &mut filtered);
+                *sample = filtered[0];
+            }
+        }
+
+        let start = out.len();
+        match self.suppressor.as_mut() {
+            Some(suppressor) => suppressor.process(&self.buffer, out),
+            None => out.extend_from_slice(&self.buffer),
+        }
+
+        if let Some(gate) = self.gate.as_mut() {
+            out[start..]
+                .iter_mut()
+                .for_each(|sample| *sample = gate.process(*sample));
+        }
+    }
+}
+
+#[derive(Debug)]
+struct NoiseGate {
+    threshold: f32,
+    envelope: f32,
+    gain: f32,
+    envelope_release: f32,
+    attack: f32,
+    release: f32,
+}
+
+impl NoiseGate {
+    fn new(threshold: f32) -> Self {
+        Self {
+            threshold: threshold.min(0.),
+            envelope: 0.,
+            gain: 0.,
+            envelope_release: time_coefficient(GATE_ENVELOPE_RELEASE),
+            attack: time_coefficient(GATE_ATTACK),
+            release: time_coefficient(GATE_RELEASE),
+        }
+    }
+
+    fn process(&mut self, sample: AudioSampleType) -> AudioSampleType {
+        self.envelope = sample.abs().max(self.envelope * self.envelope_release);
+
+        let open = 20. * self.envelope.max(f32::MIN_POSITIVE).log10() >= self.threshold;
+        let (target, coefficient) = match open {
+            true => (1., self.attack),
+            false => (0., self.release),
+        };
+        self.gain = target + (self.gain - target) * coefficient;
+
+        sample * self.gain
+    }
+}
+
+/// spectral subtraction with a noise floor that follows the quietest bins
+#[derive(Debug)]
+struct SpectralSuppressor {
+    strength: f32,
+    pending: AudioSampleVec,
+    history: Vec<f32>,
+    overlap: Vec<f32>,
+    window: Vec<f32>,
+    real: Vec<f32>,
+    imaginary: Vec<f32>,
+    power: Vec<f32>,
+    noise: Vec<f32>,
+    gains: Vec<f32>,
+}
+
+impl SpectralSuppressor {
+    fn new(strength: f32) -> Self {
+        let bins = FFT_SIZE / 2 + 1;
+
+        Self {
+            strength: strength.clamp(0., 1.),
+            pending: AudioSampleVec::with_capacity(HOP_SIZE),
+            history: vec![0.; FFT_SIZE],
+            overlap: vec![0.; HOP_SIZE],
+            // the square root of a hann window, applied twice it adds up to 1 at half overlap
+            window: (0..FFT_SIZE)
+                .map(|i| (PI * i as f32 / FFT_SIZE as f32).sin())
+                .collect(),
+            real: vec![0.; FFT_SIZE],
+            imaginary: vec![0.; FFT_SIZE],
+            power: vec![0.; bins],
+            noise: vec![f32::INFINITY; bins],
+            gains: vec![1.; bins],
+        }
+    }
+
+    fn process(&mut self, input: &[AudioSampleType], out: &mut AudioSampleVec) {
+        for sample in input {
+            self.pending.push(*sample);
+
+            if self.pending.len() == HOP_SIZE {
+                self.process_hop(out);
+                self.pending.clear();
+            }
+        }
+    }
+
+    fn process_hop(&mut self, out: &mut AudioSampleVec) {
+        self.history.copy_within(HOP_SIZE.., 0);
+        self.history[FFT_SIZE - HOP_SIZE..].copy_from_slice(&self.pending);
+
+        for i in 0..FFT_SIZE {
+            self.real[i] = self.history[i] * self.window[i];
+            self.imaginary[i] = 0.;
+        }
+        fft(&mut self.real, &mut self.imaginary, false);
+
+        for bin in 0..self.power.len() {
+            let power = self.real[bin].powi(2) + self.imaginary[bin].powi(2);
+            self.power[bin] += (power - self.power[bin]) * (1. - SPECTRAL_SMOOTHING);
+
+            let noise = &mut self.noise[bin];
+            if self.power[bin] < *noise {
+                *noise = self.power[bin];
+            } else if self.power[bin] < *noise * SPEECH_RATIO {
+                *noise += (self.power[bin] - *noise) * NOISE_RISE;
+            } else {
+                *noise += (self.power[bin] - *noise) * SPEECH_NOISE_RISE;
+            }
+
+            let gain = (1.
+                - self.strength * OVERSUBTRACTION * *noise / self.power[bin].max(f32::EPSILON))
+            .max(SUPPRESSION_FLOOR);
+            self.gains[bin] += (gain - self.gains[bin]) * (1. - SPECTRAL_SMOOTHING);
+
+            // the dc and nyquist bins are their own mirror
+            let mirrored = (FFT_SIZE - bin) % FFT_SIZE;
+            self.real[bin] *= self.gains[bin];
+            self.imaginary[bin] *= self.gains[bin];
+            if mirrored != bin {
+                self.real[mirrored] *= self.gains[bin];
+                self.imaginary[mirrored] *= self.gains[bin];
+            }
+        }
+        fft(&mut self.real, &mut self.imaginary, true);
+
+        for i in 0..FFT_SIZE {
+            self.real[i] *= self.window[i] / FFT_SIZE as f32;
+        }
+
+        out.extend(
+            self.overlap
+                .iter()
+                .zip(&self.real[..HOP_SIZE])
+                .map(|(overlap, sample)| overlap + sample),
+        );
+        self.overlap.copy_from_slice(&self.real[HOP_SIZE..]);
+    }
+}
+
+/// in place radix 2 fft, `inverse` doesn't scale the output
+fn fft(real: &mut [f32], imaginary: &mut [f32], inverse: bool) {
+    let n = real.len();
+
+    let mut j = 0;
+    for i in 1..n {
+        let mut bit = n >> 1;
+        while j & bit != 0 {
+            j ^= bit;
+            bit >>= 1;
+        }
+        j |= bit;
+
+        if i < j {
+            real.swap(i, j);
+            imaginary.swap(i, j);
+        }
+    }
+
+    let sign = if inverse { 1. } else { -1. };
+    let mut len = 2;
+    while len <= n {
+        let angle = sign * 2. * PI / len as f32;
+
+        for start in (0..n).step_by(len) {
+            for k in 0..len / 2 {
+                let (sin, cos) = (angle * k as f32).sin_cos();
+                let (a, b) = (start + k, start + k + len / 2);
+
+                let t_real = real[b] * cos - imaginary[b] * sin;
+                let t_imaginary = real[b] * sin + imaginary[b] * cos;
+
+                real[b] = real[a] - t_real;
+                imaginary[b] = imaginary[a] - t_imaginary;
+                real[a] += t_real;
+                imaginary[a] += t_imaginary;
+            }
+        }
+
+        len <<= 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// a bin of the suppressor's fft so the tone doesn't leak into its neighbours
+    const TONE: f32 = 6. * WIRE_SAMPLE_RATE as f32 / FFT_SIZE as f32;
+
+    fn noise(samples: usize) -> Vec<f32> {
+        let mut state = 1u32;
+        (0..samples)
+            .map(|_| {
+                state = state.wrapping_mul(1664525).wrapping_add(1013904223);
+                (state as f32 / u32::MAX as f32 - 0.5) * 0.1
+            })
+            .collect()
+    }
+
+    fn rms(audio: &[f32]) -> f32 {
+        (audio.iter().map(|sample| sample * sample).sum::<f32>() / audio.len() as f32).sqrt()
+    }
+
+    /// how loud `TONE` is in `audio`
+    fn tone_amplitude(audio: &[f32]) -> f32 {
+        let (sin, cos) = audio
+            .iter()
+            .enumerate()
+            .map(|(i, sample)| {
+                let (sin, cos) = (2. * PI * TONE * i as f32 / WIRE_SAMPLE_RATE as f32).sin_cos();
+                (sample * sin, sample * cos)
+            })
+            .fold((0., 0.), |(a, b), (sin, cos)| (a + sin, b + cos));
+
+        2. * (sin * sin + cos * cos).sqrt() / audio.len() as f32
+    }
+
+    fn denoise(input: &[f32]) -> Vec<f32> {
+        let mut denoiser = Denoiser::default();
+        denoiser.set_suppression(Some(1.));
+
+        let mut out = AudioSampleVec::new();
+        input
+            .chunks(AUDIO_BUFFER_SIZE)
+            .for_each(|frame| denoiser.process(frame, &mut out));
+        out
+    }
+
+    #[test]
+    fn steady_noise_is_reduced() {
+        let second = WIRE_SAMPLE_RATE as usize;
+        let input = noise(2 * second);
+        let out = denoise(&input);
+
+        assert!(rms(&out[second..]) < 0.5 * rms(&input[second..]));
+    }
+
+    #[test]
+    fn held_tone_survives() {
+        let second = WIRE_SAMPLE_RATE as usize;
+        let mut input = noise(3 * second);
+        for (i, sample) in input[2 * second..].iter_mut().enumerate() {
+            *sample += 0.1 * (2. * PI * TONE * i as f32 / WIRE_SAMPLE_RATE as f32).sin();
+        }
+        let out = denoise(&input);
+
+        assert!(tone_amplitude(&out[2 * second..]) > 0.8 * tone_amplitude(&input[2 * second..]));
+    }
+}
@@ -0,0 +1,119 @@
+use std::collections::VecDeque;
+
+use crate::shared::{time_coefficient, AudioSampleType, OUTPUT_CHANNELS, WIRE_SAMPLE_RATE};
+
+/// the level the agc aims for, in dBFS
+pub const DEFAULT_AGC_TARGET: f32 = -20.;
+/// quieter than this is treated as silence and left alone, in dBFS
+const AGC_NOISE_FLOOR: f32 = -50.;
+const AGC_MAX_BOOST: f32 = 20.;
+const AGC_MAX_CUT: f32 = 20.;
+/// the loudness is measured over about this long, in seconds
+const AGC_WINDOW: f32 = 0.3;
+/// how fast the gain moves, in dB per second, cutting is faster so loud players don't blast anyone
+const AGC_BOOST_RATE: f32 = 6.;
+const AGC_CUT_RATE: f32 = 30.;
+
+/// the limiter never lets a sample go past this
+const LIMITER_CEILING: f32 = 0.98;
+/// how far ahead the limiter looks, 1.5 ms
+const LIMITER_LOOKAHEAD: usize = (WIRE_SAMPLE_RATE as usize * 15) / 10_000;
+/// how fast the limiter recovers, in seconds
+const LIMITER_RELEASE: f32 = 0.1;
+/// the gain gets most of the way down within the look ahead
+const LIMITER_ATTACK: f32 = LIMITER_LOOKAHEAD as f32 / WIRE_SAMPLE_RATE as f32 / 4.;
+
+/// normalizes the loudness of the microphone
+#[derive(Debug)]
+pub struct Agc {
+    /// in dBFS
+    pub target: f32,
+    power: f32,
+    gain: f32,
+    window: f32,
+}
+
+impl Agc {
+    pub fn new(target: f32) -> Self {
+        Self {
+            target: target.min(0.),
+            power: 0.,
+            gain: 0.,
+            window: 1. - time_coefficient(AGC_WINDOW),
+        }
+    }
+
+    pub fn process(&mut self, audio: &mut [AudioSampleType]) {
+        let boost_step = AGC_BOOST_RATE / WIRE_SAMPLE_RATE as f32;
+        let cut_step = AGC_CUT_RATE / WIRE_SAMPLE_RATE as f32;
+
+        for sample in audio.iter_mut() {
+            self.power += (*sample * *sample - self.power) * self.window;
+
+            let level = 10. * self.power.max(f32::MIN_POSITIVE).log10();
+            if level > AGC_NOISE_FLOOR {
+                let wanted = (self.target - level).clamp(-AGC_MAX_CUT, AGC_MAX_BOOST);
+                self.gain += (wanted - self.gain).clamp(-cut_step, boost_step);
+            }
+
+            *sample *= db_to_gain(self.gain);
+        }
+    }
+}
+
+/// a stereo linked look ahead limiter, the output is `LIMITER_LOOKAHEAD` samples late
+#[derive(Debug)]
+pub struct Limiter {
+    delay: VecDeque<[AudioSampleType; OUTPUT_CHANNELS]>,
+    required_gains: VecDeque<f32>,
+    gain: f32,
+    attack: f32,
+    release: f32,
+}
+
+impl Default for Limiter {
+    fn default() -> Self {
+        Self {
+            delay: VecDeque::from(vec![[0.; OUTPUT_CHANNELS]; LIMITER_LOOKAHEAD]),
+            required_gains: VecDeque::from(vec![1.; LIMITER_LOOKAHEAD]),
+            gain: 1.,
+            attack: time_coefficient(LIMITER_ATTACK),
+            release: time_coefficient(LIMITER_RELEASE),
+        }
+    }
+}
+
+impl Limiter {
+    /// limits an interleaved stereo mix in place
+    pub fn process(&mut self, mix: &mut [AudioSampleType]) {
+        for frame in mix.chunks_exact_mut(OUTPUT_CHANNELS) {
+            let peak = frame
+                .iter()
+                .fold(0., |peak: f32, sample| peak.max(sample.abs()));
+
+            self.delay.push_back([frame[0], frame[1]]);
+            self.required_gains
+                .push_back((LIMITER_CEILING / peak.max(f32::MIN_POSITIVE)).min(1.));
+
+            let delayed = self.delay.pop_front().unwrap_or_default();
+            self.required_gains.pop_front();
+
+            // the gain is already down when a peak comes out of the delay
+            let target = self.required_gains.iter().copied().fold(1., f32::min);
+            let coefficient = if target < self.gain {
+                self.attack
+            } else {
+                self.release
+            };
+            self.gain = target + (self.gain - target) * coefficient;
+
+            for (sample, delayed) in frame.iter_mut().zip(delayed) {
+                *sample = (delayed * self.gain).clamp(-LIMITER_CEILING, LIMITER_CEILING);
+            }
+        }
+    }
+}
+
+fn db_to_gain(db: f32) -> f32 {
+    10f32.powf(db / 20.)
+}
@@ -0,0 +1,196 @@
+use std::collections::VecDeque;
+
+use crate::shared::{AudioSampleType, AudioSampleVec, WIRE_SAMPLE_RATE};
+
+/// length of the adaptive filter, about 10 ms of echo tail after the bulk delay
+const FILTER_TAPS: usize = 512;
+/// the longest delay between playing a sample and hearing it in the microphone, 200 ms
+const MAX_DELAY: usize = WIRE_SAMPLE_RATE as usize / 5;
+/// how much capture is correlated with the playback to find the delay, 100 ms
+const ESTIMATE_WINDOW: usize = WIRE_SAMPLE_RATE as usize / 10;
+/// the delay is looked for again this often, 0.5 s
+const ESTIMATE_INTERVAL: usize = WIRE_SAMPLE_RATE as usize / 2;
+/// the delay search only looks at every this many samples and lags
+const ESTIMATE_DECIMATION: usize = 4;
+/// a delay is only trusted above this normalized correlation
+const MIN_CORRELATION: f32 = 0.3;
+/// nlms step size
+const STEP_SIZE: f32 = 0.3;
+/// adaptation stops while the microphone is louder than this compared to the playback, someone is talking
+const DOUBLE_TALK_RATIO: f32 = 1.;
+/// playback that isn't matched by capture gets dropped past this, the two clocks drift apart
+const MAX_PENDING_REFERENCE: usize = WIRE_SAMPLE_RATE as usize / 2;
+
+const HISTORY_SIZE: usize = MAX_DELAY + FILTER_TAPS + ESTIMATE_WINDOW;
+
+/// removes what the speakers played from the microphone
+///
+/// the playback is the reference, its bulk delay is found by cross correlation
+/// and the rest of the echo path is learned by a nlms filter
+#[derive(Debug)]
+pub struct EchoCanceller {
+    pending_reference: VecDeque<AudioSampleType>,
+    history: AudioSampleVec,
+    capture_history: AudioSampleVec,
+    weights: Vec<f32>,
+    delay: usize,
+    since_estimate: usize,
+}
+
+impl Default for EchoCanceller {
+    fn default() -> Self {
+        Self {
+            pending_reference: VecDeque::new(),
+            history: vec![0.; HISTORY_SIZE],
+            capture_history: vec![0.; ESTIMATE_WINDOW],
+            weights: vec![0.; FILTER_TAPS],
+            delay: 0,
+            since_estimate: 0,
+        }
+    }
+}
+
+impl EchoCanceller {
+    /// the mono playback as it's handed to the output device
+    pub fn push_reference(&mut self, reference: &[AudioSampleType]) {
+        self.pending_reference.extend(reference);
+
+        if self.pending_reference.len() > MAX_PENDING_REFERENCE {
+            let excess = self.pending_reference.len() - MAX_PENDING_REFERENCE;
+            self.pending_reference.drain(..excess);
+        }
+    }
+
+    /// cancels the echo from mono microphone audio in place
+    pub fn process(&mut self, capture: &mut [AudioSampleType]) {
+        for sample in capture.iter_mut() {
+            // every captured sample moves the playback forward by one too
+            let reference = self.pending_reference.pop_front().unwrap_or_default();
+            push_bounded(&mut self.history, reference, HISTORY_SIZE);
+            push_bounded(&mut self.capture_history, *sample, ESTIMATE_WINDOW);
+
+            let end = self.history.len() - self.delay;
+            let window = &self.history[end - FILTER_TAPS..end];
+
+            let estimate = window
+                .iter()
+                .zip(&self.weights)
+                .map(|(x, w)| x * w)
+                .sum::<f32>();
+            let error = *sample - estimate;
+
+            let (energy, peak) = window.iter().fold((0., 0.), |(energy, peak), x| {
+                (energy + x * x, f32::max(peak, x.abs()))
+            });
+            if peak > 0. && sample.abs() < peak * DOUBLE_TALK_RATIO {
+                let step = STEP_SIZE * error / (energy + f32::EPSILON);
+                self.weights
+                    .iter_mut()
+                    .zip(window)
+                    .for_each(|(w, x)| *w += step * x);
+            }
+
+            *sample = error;
+
+            self.since_estimate += 1;
+            if self.since_estimate >= ESTIMATE_INTERVAL {
+                self.since_estimate = 0;
+                self.estimate_delay();
+            }
+        }
+    }
+
+    /// finds the lag where the playback lines up best with the capture
+    fn estimate_delay(&mut self) {
+        let capture = &self.capture_history[self.capture_history.len() - ESTIMATE_WINDOW..];
+        let capture_energy = capture
+            .iter()
+            .step_by(ESTIMATE_DECIMATION)
+            .map(|c| c * c)
+            .sum::<f32>();
+        if capture_energy <= f32::EPSILON {
+            return;
+        }
+
+        let len = self.history.len();
+        let best = (0..MAX_DELAY)
+            .step_by(ESTIMATE_DECIMATION)
+            .map(|lag| {
+                let reference = &self.history[len - lag - ESTIMATE_WINDOW..len - lag];
+                let (correlation, energy) = capture
+                    .iter()
+                    .zip(reference)
+                    .step_by(ESTIMATE_DECIMATION)
+                    .fold((0., 0.), |(correlation, energy), (c, x)| {
+                        (correlation + c * x, energy + x * x)
+                    });
+
+                let normalized =
+                    correlation.abs() / (capture_energy * energy).sqrt().max(f32::EPSILON);
+                (lag, normalized)
+            })
+            .max_by(|(_, a), (_, b)| a.total_cmp(b));
+
+        let Some((lag, correlation)) = best else {
+            return;
+        };
+
+        // start the filter a little before the direct path so it can be learned
+        let delay = lag.saturating_sub(FILTER_TAPS / 8);
+        if correlation >= MIN_CORRELATION && delay.abs_diff(self.delay) > FILTER_TAPS / 4 {
+            log::info!(
+                "echo delay is now {} ms",
+                delay * 1000 / WIRE_SAMPLE_RATE as usize
+            );
+            self.delay = delay;
+            self.weights.fill(0.);
+        }
+    }
+}
+
+/// keeps at least the last `len` samples, trimmed in batches so it stays contiguous
+fn push_bounded(buffer: &mut AudioSampleVec, sample: AudioSampleType, len: usize) {
+    if buffer.len() >= len * 2 {
+        buffer.drain(..buffer.len() - len);
+    }
+    buffer.push(sample);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::shared::AUDIO_BUFFER_SIZE;
+
+    #[test]
+    fn delayed_echo_is_cancelled() {
+        let second = WIRE_SAMPLE_RATE as usize;
+        let echo_delay = second / 20;
+
+        let mut state = 1u32;
+        let far_end = (0..2 * second)
+            .map(|_| {
+                state = state.wrapping_mul(1664525).wrapping_add(1013904223);
+                state as f32 / u32::MAX as f32 - 0.5
+            })
+            .collect::<Vec<_>>();
+        let capture = (0..far_end.len())
+            .map(|i| i.checked_sub(echo_delay).map_or(0., |i| 0.5 * far_end[i]))
+            .collect::<Vec<_>>();
+
+        let mut canceller = EchoCanceller::default();
+        let mut residual = capture.clone();
+        for (reference, frame) in far_end
+            .chunks(AUDIO_BUFFER_SIZE)
+            .zip(residual.chunks_mut(AUDIO_BUFFER_SIZE))
+        {
+            canceller.push_reference(reference);
+            canceller.process(frame);
+        }
+
+        let energy = |audio: &[f32]| audio.iter().map(|sample| sample * sample).sum::<f32>();
+        let last_second = capture.len() - second..;
+        let erle =
+            10. * (energy(&capture[last_second.clone()]) / energy(&residual[last_second])).log10();
+        assert!(erle > 20., "only {erle} dB of echo was removed");
+    }
+}
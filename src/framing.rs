@@ -0,0 +1,254 @@
+use std::{
+    io::{self, Read, Write},
+    mem::size_of,
+};
+
+use crate::{
+    crypto::{OpeningKey, SealingKey},
+    shared::{NetPacket, ProxiChatError, READ_BUFFER_SIZE},
+};
+
+/// every packet on the tcp stream is prefixed by its length as a little endian u32
+pub const FRAME_HEADER_SIZE: usize = size_of::<u32>();
+/// anything bigger than this is garbage or a broken peer
+pub const MAX_FRAME_SIZE: usize = 64 * 1024;
+
+/// reassembles packets from a stream that doesn't keep message boundaries
+///
+/// partial frames stay in the buffer until the rest of them arrives
+#[derive(Debug)]
+pub struct PacketReader {
+    buffer: Vec<u8>,
+    read_buffer: Vec<u8>,
+    /// frames are decrypted once the key exchange is done
+    key: Option<OpeningKey>,
+}
+
+impl Default for PacketReader {
+    fn default() -> Self {
+        Self {
+            buffer: Vec::new(),
+            read_buffer: vec![0; READ_BUFFER_SIZE],
+            key: None,
+        }
+    }
+}
+
+impl PacketReader {
+    /// reads everything that is currently available on a non blocking stream
+    pub fn fill(&mut self, stream: &mut impl Read) -> Result<(), ProxiChatError> {
+        loop {
+            match stream.read(&mut self.read_buffer) {
+                Ok(0) => Err(io::Error::from(io::ErrorKind::ConnectionAborted))?,
+                Ok(size) => self.buffer.extend_from_slice(&self.read_buffer[..size]),
+                Err(err) if matches!(err.kind(), io::ErrorKind::WouldBlock) => return Ok(()),
+                Err(err) if matches!(err.kind(), io::ErrorKind::Interrupted) => {}
+                Err(err) => Err(err)?,
+            }
+        }
+    }
+
+    /// decodes the next complete packet if there is one
+    pub fn next_packet(&mut self) -> Result<Option<NetPacket>, ProxiChatError> {
+        let Some(header) = self.buffer.get(..FRAME_HEADER_SIZE) else {
+            return Ok(None);
+        };
+
+        let size = u32::from_le_bytes(header.try_into().expect("header has a fixed size")) as usize;
+        if size > MAX_FRAME_SIZE {
+            Err(ProxiChatError::FrameTooLarge(size))?
+        }
+
+        let Some(payload) = self.buffer.get(FRAME_HEADER_SIZE..FRAME_HEADER_SIZE + size) else {
+            return Ok(None);
+        };
+
+        let packet = match self.key.as_mut() {
+            Some(key) => {
+                let mut payload = payload.to_vec();
+                key.open_next(&mut payload)
+                    .and_then(|_| Ok(bincode::deserialize(&payload)?))
+            }
+            None => bincode::deserialize(payload).map_err(ProxiChatError::from),
+        };
+        self.buffer.drain(..FRAME_HEADER_SIZE + size);
+
+        Ok(Some(packet?))
+    }
+
+    /// every frame after the current one has to be encrypted with `key`
+    pub fn set_key(&mut self, key: OpeningKey) {
+        self.key = Some(key);
+    }
+
+    /// also forgets the key since it belongs to the connection
+    pub fn clear(&mut self) {
+        self.buffer.clear();
+        self.key = None;
+    }
+}
+
+/// queues framed packets and writes as much of them as the stream accepts
+///
+/// whatever didn't fit is kept for the next flush so a frame is never cut in half
+#[derive(Debug, Default)]
+pub struct PacketWriter {
+    buffer: Vec<u8>,
+    /// frames are encrypted once the key exchange is done
+    key: Option<SealingKey>,
+}
+
+impl PacketWriter {
+    pub fn queue(&mut self, packet: &NetPacket) -> Result<(), ProxiChatError> {
+        let mut payload = bincode::serialize(packet)?;
+        if let Some(key) = self.key.as_mut() {
+            key.seal(&mut payload)?;
+        }
+
+        if payload.len() > MAX_FRAME_SIZE {
+            Err(ProxiChatError::FrameTooLarge(payload.len()))?
+        }
+
+        self.buffer
+            .extend_from_slice(&(payload.len() as u32).to_le_bytes());
+        self.buffer.extend_from_slice(&payload);
+
+        Ok(())
+    }
+
+    /// every packet queued after this is encrypted with `key`
+    pub fn set_key(&mut self, key: SealingKey) {
+        self.key = Some(key);
+    }
+
+    pub fn flush(&mut self, stream: &mut impl Write) -> Result<(), ProxiChatError> {
+        while !self.buffer.is_empty() {
+            match stream.write(&self.buffer) {
+                Ok(0) => Err(io::Error::from(io::ErrorKind::WriteZero))?,
+                Ok(size) => _ = self.buffer.drain(..size),
+                Err(err) if matches!(err.kind(), io::ErrorKind::WouldBlock) => return Ok(()),
+                Err(err) if matches!(err.kind(), io::ErrorKind::Interrupted) => {}
+                Err(err) => Err(err)?,
+            }
+        }
+
+        Ok(())
+    }
+
+    /// also forgets the key since it belongs to the connection
+    pub fn clear(&mut self) {
+        self.buffer.clear();
+        self.key = None;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        auth::AuthToken,
+        crypto::{Handshake, Side},
+    };
+
+    fn keyed_pair() -> (PacketWriter, PacketReader) {
+        let token = AuthToken::generate().unwrap();
+        let client = Handshake::default();
+        let server = Handshake::default();
+        let (client_public_key, server_public_key) = (client.public_key(), server.public_key());
+        let client = client
+            .finish(Side::Client, server_public_key, token)
+            .unwrap();
+        let server = server
+            .finish(Side::Server, client_public_key, token)
+            .unwrap();
+
+        let mut writer = PacketWriter::default();
+        writer.set_key(client.stream_sealing);
+        let mut reader = PacketReader::default();
+        reader.set_key(server.stream_opening);
+        (writer, reader)
+    }
+
+    fn disconnect(reason: &str) -> NetPacket {
+        NetPacket::Disconnect {
+            reason: reason.to_string(),
+        }
+    }
+
+    fn reason(packet: Option<NetPacket>) -> String {
+        match packet {
+            Some(NetPacket::Disconnect { reason }) => reason,
+            packet => panic!("expected a disconnect, got {packet:?}"),
+        }
+    }
+
+    #[test]
+    fn sealed_packets_round_trip() {
+        let (mut writer, mut reader) = keyed_pair();
+        writer.queue(&disconnect("first")).unwrap();
+        writer.queue(&disconnect("second")).unwrap();
+
+        let mut stream = Vec::new();
+        writer.flush(&mut stream).unwrap();
+        assert!(!stream.windows(5).any(|window| window == b"first"));
+
+        // arrives in two pieces with the split inside the first frame
+        reader
+            .buffer
+            .extend_from_slice(&stream[..FRAME_HEADER_SIZE + 2]);
+        assert!(reader.next_packet().unwrap().is_none());
+        reader
+            .buffer
+            .extend_from_slice(&stream[FRAME_HEADER_SIZE + 2..]);
+
+        assert_eq!(reason(reader.next_packet().unwrap()), "first");
+        assert_eq!(reason(reader.next_packet().unwrap()), "second");
+        assert!(reader.next_packet().unwrap().is_none());
+    }
+
+    #[test]
+    fn tampered_frames_are_refused() {
+        let (mut writer, mut reader) = keyed_pair();
+        writer.queue(&disconnect("tampered")).unwrap();
+
+        let mut stream = Vec::new();
+        writer.flush(&mut stream).unwrap();
+        stream[FRAME_HEADER_SIZE] ^= 1;
+        reader.buffer.extend_from_slice(&stream);
+
+        assert!(matches!(
+            reader.next_packet(),
+            Err(ProxiChatError::DecryptionFailed)
+        ));
+    }
+
+    #[test]
+    fn replayed_frames_are_refused() {
+        let (mut writer, mut reader) = keyed_pair();
+        writer.queue(&disconnect("once")).unwrap();
+
+        let mut stream = Vec::new();
+        writer.flush(&mut stream).unwrap();
+        reader.buffer.extend_from_slice(&stream);
+        reader.buffer.extend_from_slice(&stream);
+
+        assert_eq!(reason(reader.next_packet().unwrap()), "once");
+        assert!(matches!(
+            reader.next_packet(),
+            Err(ProxiChatError::DecryptionFailed)
+        ));
+    }
+
+    #[test]
+    fn oversized_frames_are_refused() {
+        let mut reader = PacketReader::default();
+        reader
+            .buffer
+            .extend_from_slice(&(MAX_FRAME_SIZE as u32 + 1).to_le_bytes());
+
+        assert!(matches!(
+            reader.next_packet(),
+            Err(ProxiChatError::FrameTooLarge(_))
+        ));
+    }
+}
@@ -0,0 +1,175 @@
+use std::{fmt, str::FromStr, time::Duration};
+
+use crate::{
+    dynamics::{Agc, DEFAULT_AGC_TARGET},
+    shared::{AudioSampleType, AUDIO_BUFFER_SIZE, WIRE_SAMPLE_RATE},
+};
+
+/// frames under this level (in dBFS) don't open the voice activity gate
+pub const DEFAULT_VAD_THRESHOLD: f32 = -45.;
+/// the voice activity gate stays open this long after the last loud frame so words don't get cut
+const VAD_HANGOVER: Duration = Duration::from_millis(300);
+
+/// when the microphone is transmitted
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CaptureMode {
+    AlwaysOn,
+    /// only while `+proxichat_talk` (or `+proxichat_radio`) is held
+    PushToTalk,
+    /// only while the microphone is louder than the threshold
+    VoiceActivity,
+}
+
+impl FromStr for CaptureMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "always_on" => Ok(CaptureMode::AlwaysOn),
+            "push_to_talk" => Ok(CaptureMode::PushToTalk),
+            "voice_activity" => Ok(CaptureMode::VoiceActivity),
+            _ => Err(format!(
+                "unknown capture mode {s}; expected always_on, push_to_talk or voice_activity"
+            )),
+        }
+    }
+}
+
+impl fmt::Display for CaptureMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            CaptureMode::AlwaysOn => "always_on",
+            CaptureMode::PushToTalk => "push_to_talk",
+            CaptureMode::VoiceActivity => "voice_activity",
+        })
+    }
+}
+
+/// decides which captured frames get sent, closed frames aren't sent at all
+#[derive(Debug)]
+pub struct CaptureGate {
+    pub mode: CaptureMode,
+    /// in dBFS
+    pub threshold: f32,
+    /// levels the frames that get sent
+    pub agc: Option<Agc>,
+    hangover_frames: usize,
+}
+
+impl Default for CaptureGate {
+    fn default() -> Self {
+        Self {
+            mode: CaptureMode::AlwaysOn,
+            threshold: DEFAULT_VAD_THRESHOLD,
+            agc: Some(Agc::new(DEFAULT_AGC_TARGET)),
+            hangover_frames: 0,
+        }
+    }
+}
+
+impl CaptureGate {
+    /// `key_held` is true while a talk key is held
+    pub fn is_open(&mut self, frame: &[AudioSampleType], key_held: bool) -> bool {
+        match self.mode {
+            CaptureMode::AlwaysOn => true,
+            CaptureMode::PushToTalk => key_held,
+            CaptureMode::VoiceActivity => {
+                if key_held || level(frame) >= self.threshold {
+                    self.hangover_frames = hangover_frames();
+                    return true;
+                }
+
+                self.hangover_frames = self.hangover_frames.saturating_sub(1);
+                self.hangover_frames != 0
+            }
+        }
+    }
+
+    /// decides on the level the microphone really has and only levels the frames that get sent
+    ///
+    /// otherwise the agc boosts room noise over the threshold and its gain runs away while
+    /// nothing is sent
+    pub fn process(&mut self, frame: &mut [AudioSampleType], key_held: bool) -> bool {
+        if !self.is_open(frame, key_held) {
+            return false;
+        }
+
+        if let Some(agc) = self.agc.as_mut() {
+            agc.process(frame);
+        }
+        true
+    }
+}
+
+/// rms level of a frame in dBFS
+fn level(frame: &[AudioSampleType]) -> f32 {
+    let power = frame.iter().map(|sample| sample * sample).sum::<f32>() / frame.len().max(1) as f32;
+
+    10. * power.max(f32::MIN_POSITIVE).log10()
+}
+
+fn hangover_frames() -> usize {
+    (VAD_HANGOVER.as_secs_f32() * WIRE_SAMPLE_RATE as f32 / AUDIO_BUFFER_SIZE as f32) as usize
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// a steady 440 hz tone at `level` dBFS rms
+    fn frames(level: f32, seconds: f32) -> impl Iterator<Item = Vec<AudioSampleType>> {
+        let amplitude = std::f32::consts::SQRT_2 * 10f32.powf(level / 20.);
+        let count = (seconds * WIRE_SAMPLE_RATE as f32) as usize / AUDIO_BUFFER_SIZE;
+
+        (0..count).map(move |frame| {
+            (0..AUDIO_BUFFER_SIZE)
+                .map(|i| {
+                    let t = (frame * AUDIO_BUFFER_SIZE + i) as f32 / WIRE_SAMPLE_RATE as f32;
+                    amplitude * (std::f32::consts::TAU * 440. * t).sin()
+                })
+                .collect()
+        })
+    }
+
+    fn voice_activity_gate() -> CaptureGate {
+        CaptureGate {
+            mode: CaptureMode::VoiceActivity,
+            ..CaptureGate::default()
+        }
+    }
+
+    #[test]
+    fn room_noise_doesnt_open_the_gate_through_the_agc() {
+        let mut gate = voice_activity_gate();
+
+        let open = frames(DEFAULT_VAD_THRESHOLD - 3., 10.)
+            .filter(|frame| gate.process(&mut frame.clone(), false))
+            .count();
+        assert_eq!(open, 0);
+    }
+
+    #[test]
+    fn speech_opens_the_gate_and_is_leveled() {
+        let mut gate = voice_activity_gate();
+
+        let mut last_level = f32::NEG_INFINITY;
+        for mut frame in frames(-30., 3.) {
+            assert!(gate.process(&mut frame, false));
+            last_level = level(&frame);
+        }
+        assert!((last_level - DEFAULT_AGC_TARGET).abs() < 1., "{last_level}");
+    }
+
+    #[test]
+    fn stays_open_for_the_hangover() {
+        let mut gate = voice_activity_gate();
+        let silence = [0.; AUDIO_BUFFER_SIZE];
+
+        assert!(!gate.is_open(&silence, false));
+        assert!(gate.is_open(&silence, true));
+        let open = (0..hangover_frames() * 2)
+            .take_while(|_| gate.is_open(&silence, false))
+            .count();
+        assert_eq!(open, hangover_frames() - 1);
+    }
+}
@@ -0,0 +1,99 @@
+use std::time::{Duration, Instant};
+
+use crate::{
+    framing::PacketWriter,
+    shared::{launch_arg, NetPacket, ProxiChatError},
+};
+
+/// how long a connection can go without receiving anything before it's dropped
+pub const DEFAULT_IDLE_TIMEOUT: Duration = Duration::from_secs(10);
+/// pings per timeout, so a late pong or two doesn't drop the connection
+const PINGS_PER_TIMEOUT: u32 = 4;
+
+/// notices when the other side of a connection is gone without closing it
+///
+/// both sides ping each other and answer pings, any packet counts as a sign of life
+#[derive(Debug)]
+pub struct Heartbeat {
+    timeout: Duration,
+    last_received: Instant,
+    last_ping: Option<Instant>,
+    pong_due: bool,
+}
+
+impl Heartbeat {
+    pub fn new(timeout: Duration) -> Self {
+        Self {
+            timeout,
+            last_received: Instant::now(),
+            last_ping: None,
+            pong_due: false,
+        }
+    }
+
+    pub fn received(&mut self, packet: &NetPacket) {
+        self.last_received = Instant::now();
+
+        if matches!(packet, NetPacket::Ping) {
+            self.pong_due = true;
+        }
+    }
+
+    /// queues the pong for the last ping and a new ping when it's time for one
+    pub fn queue(&mut self, writer: &mut PacketWriter) -> Result<(), ProxiChatError> {
+        if std::mem::take(&mut self.pong_due) {
+            writer.queue(&NetPacket::Pong)?;
+        }
+
+        if self
+            .last_ping
+            .map(|last_ping| last_ping.elapsed() >= self.timeout / PINGS_PER_TIMEOUT)
+            .unwrap_or(true)
+        {
+            writer.queue(&NetPacket::Ping)?;
+            self.last_ping = Some(Instant::now());
+        }
+
+        Ok(())
+    }
+
+    pub fn timed_out(&self) -> bool {
+        self.last_received.elapsed() >= self.timeout
+    }
+}
+
+/// `-proxichat_timeout <seconds>` or `DEFAULT_IDLE_TIMEOUT`
+pub fn idle_timeout() -> Duration {
+    match launch_arg("-proxichat_timeout").map(|timeout| parse_timeout(&timeout)) {
+        Some(Ok(timeout)) => timeout,
+        Some(Err(err)) => {
+            log::warn!("invalid -proxichat_timeout: {err}; using {DEFAULT_IDLE_TIMEOUT:?}");
+            DEFAULT_IDLE_TIMEOUT
+        }
+        None => DEFAULT_IDLE_TIMEOUT,
+    }
+}
+
+fn parse_timeout(timeout: &str) -> Result<Duration, String> {
+    let seconds = timeout.parse::<f32>().map_err(|err| err.to_string())?;
+
+    // also refuses what doesn't fit in a duration instead of panicking
+    match Duration::try_from_secs_f32(seconds) {
+        Ok(duration) if !duration.is_zero() => Ok(duration),
+        _ => Err(timeout.to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn timeouts_are_parsed() {
+        assert_eq!(parse_timeout("2.5"), Ok(Duration::from_secs_f32(2.5)));
+
+        for timeout in ["0", "-1", "nan", "inf", "1e20", "soon"] {
+            assert!(parse_timeout(timeout).is_err(), "{timeout}");
+        }
+    }
+}
@@ -0,0 +1,283 @@
+use std::{collections::BTreeMap, time::Instant};
+
+use crate::{
+    shared::{AudioSampleType, AudioSampleVec, AUDIO_BUFFER_SIZE, DEFAULT_FILL_SAMPLE},
+    voice::is_newer_sequence,
+};
+
+/// the delay never goes under this many frames
+pub const MIN_TARGET_FRAMES: usize = 2;
+/// nor over this many
+pub const MAX_TARGET_FRAMES: usize = 32;
+/// frames over the target that are tolerated before some get dropped
+const DROP_THRESHOLD_FRAMES: usize = 2;
+/// missing frames in a row that get concealed before falling back to silence
+const MAX_CONCEALED_FRAMES: usize = 4;
+/// gain applied to every concealed frame on top of the previous one
+const CONCEALMENT_FADE: f32 = 0.6;
+
+/// holds sequenced frames from the server and plays them out at a steady delay
+///
+/// the delay follows the measured arrival jitter (the rfc 3550 estimator)
+/// and missing frames are concealed by repeating the last one while fading it out
+#[derive(Debug)]
+pub struct JitterBuffer {
+    frames: BTreeMap<u32, AudioSampleVec>,
+    next_sequence: Option<u32>,
+    buffering: bool,
+    current: AudioSampleVec,
+    position: usize,
+    last_frame: AudioSampleVec,
+    concealed_frames: usize,
+    frame_duration: f32,
+    last_arrival: Option<(u32, Instant)>,
+    jitter: f32,
+    target_frames: usize,
+}
+
+impl JitterBuffer {
+    pub fn new(sample_rate: u32) -> Self {
+        Self {
+            frames: BTreeMap::new(),
+            next_sequence: None,
+            buffering: true,
+            current: Vec::with_capacity(AUDIO_BUFFER_SIZE),
+            position: 0,
+            last_frame: Vec::with_capacity(AUDIO_BUFFER_SIZE),
+            concealed_frames: 0,
+            frame_duration: AUDIO_BUFFER_SIZE as f32 / sample_rate.max(1) as f32,
+            last_arrival: None,
+            jitter: 0.,
+            target_frames: MIN_TARGET_FRAMES,
+        }
+    }
+
+    pub fn push(&mut self, sequence: u32, frame: AudioSampleVec) {
+        self.update_jitter(sequence, Instant::now());
+
+        // too late, its slot was already played
+        if let Some(next_sequence) = self.next_sequence {
+            if sequence != next_sequence && !is_newer_sequence(sequence, Some(next_sequence)) {
+                return;
+            }
+        }
+
+        self.frames.insert(sequence, frame);
+    }
+
+    /// fills the device buffer, plays silence while there isn't enough buffered
+    pub fn fill(&mut self, data: &mut [AudioSampleType]) {
+        for sample in data.iter_mut() {
+            if self.position >= self.current.len() && !self.next_frame() {
+                *sample = DEFAULT_FILL_SAMPLE;
+                continue;
+            }
+
+            *sample = self.current[self.position];
+            self.position += 1;
+        }
+    }
+
+    fn next_frame(&mut self) -> bool {
+        if self.buffering {
+            if self.frames.len() < self.target_frames {
+                return false;
+            }
+            self.buffering = false;
+        }
+
+        // stay close to the target so the delay doesn't grow forever
+        if self.frames.len() > self.target_frames + DROP_THRESHOLD_FRAMES {
+            while self.frames.len() > self.target_frames {
+                self.frames.pop_first();
+            }
+            self.next_sequence = self.frames.first_key_value().map(|(sequence, _)| *sequence);
+        }
+
+        let is_next = match (self.frames.first_key_value(), self.next_sequence) {
+            (Some((sequence, _)), Some(next_sequence)) => *sequence == next_sequence,
+            (Some(_), None) => true,
+            (None, _) => false,
+        };
+
+        if !is_next && self.conceal() {
+            return true;
+        }
+
+        let Some((sequence, frame)) = self.frames.pop_first() else {
+            self.buffering = true;
+            self.last_frame.clear();
+            // a pause in speech isn't network jitter
+            self.last_arrival = None;
+            self.jitter = 0.;
+            return false;
+        };
+
+        self.last_frame = frame;
+        self.current.clone_from(&self.last_frame);
+        self.position = 0;
+        self.next_sequence = Some(sequence.wrapping_add(1));
+        self.concealed_frames = 0;
+
+        true
+    }
+
+    /// synthesizes the missing frame from the last one that was played
+    fn conceal(&mut self) -> bool {
+        if self.last_frame.is_empty() || self.concealed_frames >= MAX_CONCEALED_FRAMES {
+            return false;
+        }
+
+        let start_gain = CONCEALMENT_FADE.powi(self.concealed_frames as i32);
+        let end_gain = start_gain * CONCEALMENT_FADE;
+        let len = self.last_frame.len() as f32;
+
+        self.current.clear();
+        self.current.extend(
+            self.last_frame.iter().enumerate().map(|(i, sample)| {
+                sample * (start_gain + (end_gain - start_gain) * i as f32 / len)
+            }),
+        );
+        self.position = 0;
+        self.concealed_frames += 1;
+
+        // on an underrun the frame might only be late so its slot is kept
+        if !self.frames.is_empty() {
+            self.next_sequence = self.next_sequence.map(|sequence| sequence.wrapping_add(1));
+        }
+
+        true
+    }
+
+    fn update_jitter(&mut self, sequence: u32, arrival: Instant) {
+        if let Some((last_sequence, last_arrival)) = self.last_arrival {
+            let sent_delta =
+                sequence.wrapping_sub(last_sequence) as i32 as f32 * self.frame_duration;
+            let arrival_delta = arrival
+                .saturating_duration_since(last_arrival)
+                .as_secs_f32();
+
+            self.jitter += ((arrival_delta - sent_delta).abs() - self.jitter) / 16.;
+        }
+        self.last_arrival = Some((sequence, arrival));
+
+        self.target_frames = ((self.jitter * 2. / self.frame_duration).ceil() as usize + 1)
+            .clamp(MIN_TARGET_FRAMES, MAX_TARGET_FRAMES);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::shared::WIRE_SAMPLE_RATE;
+    use std::time::Duration;
+
+    const FRAME: usize = 4;
+
+    fn push(buffer: &mut JitterBuffer, sequence: u32) {
+        buffer.push(sequence, vec![sequence as f32 + 1.; FRAME]);
+        // arrival times in a test say nothing about the network
+        buffer.target_frames = MIN_TARGET_FRAMES;
+    }
+
+    fn fill(buffer: &mut JitterBuffer, frames: usize) -> AudioSampleVec {
+        let mut data = vec![f32::NAN; frames * FRAME];
+        buffer.fill(&mut data);
+        data
+    }
+
+    #[test]
+    fn waits_for_the_target_before_playing() {
+        let mut buffer = JitterBuffer::new(WIRE_SAMPLE_RATE);
+        push(&mut buffer, 0);
+        assert_eq!(fill(&mut buffer, 1), [DEFAULT_FILL_SAMPLE; FRAME]);
+
+        push(&mut buffer, 1);
+        assert_eq!(fill(&mut buffer, 1), [1.; FRAME]);
+    }
+
+    #[test]
+    fn reorders_frames() {
+        let mut buffer = JitterBuffer::new(WIRE_SAMPLE_RATE);
+        push(&mut buffer, 1);
+        push(&mut buffer, 0);
+        push(&mut buffer, 2);
+
+        let data = fill(&mut buffer, 3);
+        assert_eq!(data[..FRAME], [1.; FRAME]);
+        assert_eq!(data[FRAME..FRAME * 2], [2.; FRAME]);
+        assert_eq!(data[FRAME * 2..], [3.; FRAME]);
+    }
+
+    #[test]
+    fn drops_late_frames() {
+        let mut buffer = JitterBuffer::new(WIRE_SAMPLE_RATE);
+        push(&mut buffer, 0);
+        push(&mut buffer, 1);
+        fill(&mut buffer, 2);
+
+        push(&mut buffer, 0);
+        assert!(buffer.frames.is_empty());
+
+        push(&mut buffer, 2);
+        push(&mut buffer, 3);
+        assert_eq!(fill(&mut buffer, 1), [3.; FRAME]);
+    }
+
+    #[test]
+    fn conceals_a_missing_frame() {
+        let mut buffer = JitterBuffer::new(WIRE_SAMPLE_RATE);
+        push(&mut buffer, 0);
+        push(&mut buffer, 1);
+        push(&mut buffer, 3);
+
+        let data = fill(&mut buffer, 4);
+        let concealed = &data[FRAME * 2..FRAME * 3];
+        assert_eq!(concealed[0], 2.);
+        assert!(concealed.windows(2).all(|pair| pair[1] < pair[0]));
+        assert!(concealed
+            .iter()
+            .all(|sample| *sample > 2. * CONCEALMENT_FADE));
+        assert_eq!(data[FRAME * 3..], [4.; FRAME]);
+    }
+
+    #[test]
+    fn falls_back_to_silence_after_concealing() {
+        let mut buffer = JitterBuffer::new(WIRE_SAMPLE_RATE);
+        push(&mut buffer, 0);
+        push(&mut buffer, 1);
+
+        let data = fill(&mut buffer, 2 + MAX_CONCEALED_FRAMES + 1);
+        let concealed = data[FRAME * 2..FRAME * (2 + MAX_CONCEALED_FRAMES)]
+            .chunks_exact(FRAME)
+            .map(|frame| frame[0])
+            .collect::<Vec<f32>>();
+        for (i, start) in concealed.iter().enumerate() {
+            assert!((start - 2. * CONCEALMENT_FADE.powi(i as i32)).abs() < 1e-6);
+        }
+        assert_eq!(
+            data[FRAME * (2 + MAX_CONCEALED_FRAMES)..],
+            [DEFAULT_FILL_SAMPLE; FRAME]
+        );
+
+        // buffers again before the next talk spurt
+        push(&mut buffer, 2);
+        assert_eq!(fill(&mut buffer, 1), [DEFAULT_FILL_SAMPLE; FRAME]);
+    }
+
+    #[test]
+    fn pauses_dont_count_as_jitter() {
+        let mut buffer = JitterBuffer::new(WIRE_SAMPLE_RATE);
+        let start = Instant::now();
+        buffer.update_jitter(0, start);
+        buffer.frames.insert(0, vec![1.; FRAME]);
+        buffer.frames.insert(1, vec![2.; FRAME]);
+
+        // plays out the talk spurt and goes silent
+        fill(&mut buffer, 2 + MAX_CONCEALED_FRAMES + 1);
+
+        buffer.update_jitter(2, start + Duration::from_secs(3));
+        assert_eq!(buffer.jitter, 0.);
+        assert_eq!(buffer.target_frames, MIN_TARGET_FRAMES);
+    }
+}
@@ -1,16 +1,41 @@
 use rrplug::prelude::*;
-use std::env;
+use std::{env, time::Duration};
 
+mod attenuation;
+mod audio;
+mod auth;
+mod bans;
 mod bindings;
 mod client;
+mod clock;
+mod codec;
 mod connect_hook;
+mod crypto;
+mod denoise;
+mod dynamics;
+mod echo;
+mod framing;
+mod gate;
+mod heartbeat;
+mod jitter;
+mod mixer;
+mod protocol;
+mod radio;
+mod resample;
 mod server;
+mod server_commands;
 mod shared;
+mod spatial;
+mod sqfunctions;
+mod voice;
+mod volumes;
 
 use crate::{
     bindings::{EngineFunctions, ENGINE_FUNCTIONS},
-    connect_hook::setup_connect_hook,
+    connect_hook::{register_client_concommands, setup_connect_hook},
+    server_commands::register_server_concommands,
     shared::ProximityChatType,
+    sqfunctions::register_sq_functions,
 };
 
 #[derive(Debug)]
@@ -19,10 +44,12 @@ pub struct ProximityChat {
 }
 
 impl Plugin for ProximityChat {
-    fn new(_plugin_data: &PluginData) -> Self {
+    fn new(plugin_data: &PluginData) -> Self {
         // log::info!("starting a second window");
         // std::thread::spawn(move || init_window(send));
 
+        register_sq_functions(plugin_data);
+
         Self {
             proximity_chat: env::args()
                 .filter(|cmd| cmd == "-dedicated")
@@ -35,6 +62,9 @@ impl Plugin for ProximityChat {
     fn main(&self) {
         loop {
             self.proximity_chat.run_thread();
+
+            // nothing blocks anymore so this would spin otherwise, the lock is released by now
+            std::thread::sleep(Duration::from_millis(1));
         }
     }
 
@@ -42,7 +72,10 @@ impl Plugin for ProximityChat {
         unsafe { EngineFunctions::try_init(&dll_ptr, &ENGINE_FUNCTIONS) };
 
         match *engine {
-            EngineLoadType::Engine(_) => {}
+            EngineLoadType::Engine(engine) if self.proximity_chat.is_server() => {
+                register_server_concommands(engine)
+            }
+            EngineLoadType::Engine(engine) => register_client_concommands(engine),
             EngineLoadType::Client if !self.proximity_chat.is_server() => setup_connect_hook(),
             _ => {}
         }
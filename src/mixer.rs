@@ -0,0 +1,229 @@
+use rrplug::prelude::Vector3;
+
+use crate::{attenuation::Attenuation, server::PlayerState, spatial::Direction};
+
+/// the default max distance (in hammer units) a speaker can be heard from
+pub const MAX_HEARING_DISTANCE: f32 = 2000.;
+
+#[derive(Debug)]
+pub struct Speaker {
+    pub uid: i64,
+    pub position: Option<Vector3>,
+    pub team: Option<i32>,
+    pub alive: bool,
+    /// true while the speaker is talking on the team radio
+    pub radio: bool,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Listener {
+    pub uid: i64,
+    pub state: Option<PlayerState>,
+    /// where the listener hears from, the player they are spectating if there is one
+    pub view: Option<PlayerState>,
+}
+
+/// how one listener hears one speaker this frame, the client spatializes the speaker with it
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Placement {
+    pub uid: i64,
+    /// heard through the radio filter
+    pub radio: bool,
+    pub gain: f32,
+    /// `None` for voices that are the same on both ears
+    pub direction: Option<Direction>,
+}
+
+impl Listener {
+    fn alive(&self) -> bool {
+        self.state.map(|state| state.alive).unwrap_or(true)
+    }
+
+    fn team(&self) -> Option<i32> {
+        self.state.map(|state| state.team)
+    }
+}
+
+/// every other speaker the listener can hear and from where, loudest first
+///
+/// living players only hear the living: teammates on the radio at full volume from anywhere
+/// and everyone else by distance. the dead and spectators hear each other at full volume
+/// and the living around them or around who they are spectating
+pub fn place_for_listener(
+    listener: Listener,
+    speakers: &[Speaker],
+    attenuation: &Attenuation,
+) -> Vec<Placement> {
+    let mut placements = speakers
+        .iter()
+        .filter(|speaker| speaker.uid != listener.uid)
+        .filter_map(|speaker| place(listener, speaker, attenuation))
+        .collect::<Vec<Placement>>();
+    placements.sort_by(|a, b| b.gain.total_cmp(&a.gain));
+
+    placements
+}
+
+fn place(listener: Listener, speaker: &Speaker, attenuation: &Attenuation) -> Option<Placement> {
+    let centered = |radio| {
+        Some(Placement {
+            uid: speaker.uid,
+            radio,
+            gain: 1.,
+            direction: None,
+        })
+    };
+
+    match (listener.alive(), speaker.alive) {
+        (true, false) => return None,
+        (false, false) => return centered(false),
+        (true, true)
+            if speaker.radio && speaker.team.is_some() && speaker.team == listener.team() =>
+        {
+            return centered(true)
+        }
+        _ => {}
+    }
+
+    let gain = distance_gain(
+        listener.view.map(|view| view.origin),
+        speaker.position,
+        attenuation,
+    );
+    if gain <= 0. {
+        return None;
+    }
+
+    let direction = match (listener.view, speaker.position) {
+        (Some(view), Some(position)) => Direction::new(&view, position),
+        _ => Direction::CENTER,
+    };
+
+    Some(Placement {
+        uid: speaker.uid,
+        radio: false,
+        gain,
+        direction: Some(direction),
+    })
+}
+
+/// if one of the positions isn't known yet the speaker is heard at full volume
+pub fn distance_gain(
+    listener: Option<Vector3>,
+    speaker: Option<Vector3>,
+    attenuation: &Attenuation,
+) -> f32 {
+    let (Some(listener), Some(speaker)) = (listener, speaker) else {
+        return 1.;
+    };
+
+    attenuation.gain(distance(listener, speaker))
+}
+
+pub fn distance(a: Vector3, b: Vector3) -> f32 {
+    ((a.x - b.x).powi(2) + (a.y - b.y).powi(2) + (a.z - b.z).powi(2)).sqrt()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn speaker(uid: i64, alive: bool) -> Speaker {
+        Speaker {
+            uid,
+            position: None,
+            team: None,
+            alive,
+            radio: false,
+        }
+    }
+
+    fn player(x: f32, team: i32) -> PlayerState {
+        PlayerState {
+            origin: Vector3 { x, y: 0., z: 0. },
+            angles: Vector3 {
+                x: 0.,
+                y: 0.,
+                z: 0.,
+            },
+            team,
+            alive: true,
+            observer_target: None,
+        }
+    }
+
+    #[test]
+    fn who_hears_whom() {
+        let speakers = [speaker(1, true), speaker(2, true), speaker(3, false)];
+        let listener = Listener {
+            uid: 1,
+            state: None,
+            view: None,
+        };
+
+        let placements = place_for_listener(listener, &speakers, &Attenuation::default());
+
+        // nobody hears themselves and the living don't hear the dead
+        assert_eq!(
+            placements,
+            [Placement {
+                uid: 2,
+                radio: false,
+                gain: 1.,
+                direction: Some(Direction::CENTER),
+            }]
+        );
+    }
+
+    #[test]
+    fn loudest_first() {
+        let state = player(0., 1);
+        let listener = Listener {
+            uid: 1,
+            state: Some(state),
+            view: Some(state),
+        };
+        let speakers = [(2, 1500.), (3, 200.), (4, 800.)].map(|(uid, x)| Speaker {
+            position: Some(player(x, 2).origin),
+            ..speaker(uid, true)
+        });
+
+        let placements = place_for_listener(listener, &speakers, &Attenuation::default());
+        let uids = placements
+            .iter()
+            .map(|placement| placement.uid)
+            .collect::<Vec<i64>>();
+        assert_eq!(uids, [3, 4, 2]);
+    }
+
+    #[test]
+    fn teammates_on_the_radio_are_centered() {
+        let state = player(0., 1);
+        let listener = Listener {
+            uid: 1,
+            state: Some(state),
+            view: Some(state),
+        };
+        let far_away = |uid, team| Speaker {
+            position: Some(player(MAX_HEARING_DISTANCE * 2., team).origin),
+            team: Some(team),
+            radio: true,
+            ..speaker(uid, true)
+        };
+
+        let placements = place_for_listener(
+            listener,
+            &[far_away(2, 1), far_away(3, 2)],
+            &Attenuation::default(),
+        );
+        assert_eq!(
+            placements,
+            [Placement {
+                uid: 2,
+                radio: true,
+                gain: 1.,
+                direction: None,
+            }]
+        );
+    }
+}
@@ -0,0 +1,186 @@
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    codec::{Codec, SUPPORTED_CODECS},
+    shared::{launch_arg, ProxiChatError, AUDIO_BUFFER_SIZE, WIRE_SAMPLE_RATE},
+};
+
+/// both sides have to speak the same version, the server rejects everyone else
+///
+/// bump it in every release that changes what goes over the wire in a way the last release
+/// can't read or would read wrong: `NetPacket`, `VoicePacket` and everything they carry,
+/// the framing, the encryption and a codec's frame format.
+/// `NetPacket::Version` and `NetPacket::Reject` themselves never change
+pub const PROTOCOL_VERSION: u32 = 1;
+
+/// bits per second of every voice stream the server aims for without `-proxichat_bitrate`
+pub const DEFAULT_BITRATE: u32 = 64_000;
+
+/// optional parts of the protocol, unknown bits from newer builds are ignored
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Features(u32);
+
+impl Features {
+    pub const NONE: Self = Self(0);
+    /// talking to the whole team with `+proxichat_radio`
+    pub const RADIO: Self = Self(1);
+    pub const ALL: Self = Self::RADIO;
+
+    pub fn contains(self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    pub fn intersection(self, other: Self) -> Self {
+        Self(self.0 & other.0)
+    }
+}
+
+/// everything a client can do, sent with its auth for the server to pick from
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Capabilities {
+    pub codecs: Vec<Codec>,
+    /// the most bits per second the client wants on any of its voice streams
+    pub max_bitrate: u32,
+    pub sample_rates: Vec<u32>,
+    /// in samples
+    pub frame_sizes: Vec<u32>,
+    pub features: Features,
+}
+
+impl Default for Capabilities {
+    fn default() -> Self {
+        Self {
+            codecs: SUPPORTED_CODECS.to_vec(),
+            max_bitrate: bitrate_arg("-proxichat_max_bitrate").unwrap_or(u32::MAX),
+            sample_rates: vec![WIRE_SAMPLE_RATE],
+            frame_sizes: vec![AUDIO_BUFFER_SIZE as u32],
+            features: Features::ALL,
+        }
+    }
+}
+
+impl Capabilities {
+    /// the server only runs at its own sample rate and frame size, the codec follows its preference
+    /// and the bitrate is the server's unless the client wants less
+    pub fn negotiate(
+        &self,
+        preferred_codecs: &[Codec],
+        bitrate: u32,
+    ) -> Result<Settings, ProxiChatError> {
+        let codec = Codec::negotiate(preferred_codecs, &self.codecs)
+            .ok_or(ProxiChatError::NoCommonCodec)?;
+
+        if !self.sample_rates.contains(&WIRE_SAMPLE_RATE) {
+            Err(ProxiChatError::NoCommonSampleRate(WIRE_SAMPLE_RATE))?
+        }
+
+        if !self.frame_sizes.contains(&(AUDIO_BUFFER_SIZE as u32)) {
+            Err(ProxiChatError::NoCommonFrameSize(AUDIO_BUFFER_SIZE))?
+        }
+
+        Ok(Settings {
+            codec,
+            bitrate: codec.bitrate_for(bitrate.min(self.max_bitrate)),
+            sample_rate: WIRE_SAMPLE_RATE,
+            frame_size: AUDIO_BUFFER_SIZE as u32,
+            features: self.features.intersection(Features::ALL),
+        })
+    }
+}
+
+/// what the server agreed on for a connection
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct Settings {
+    pub codec: Codec,
+    /// of each voice stream, the server sends one for every speaker
+    pub bitrate: u32,
+    pub sample_rate: u32,
+    pub frame_size: u32,
+    pub features: Features,
+}
+
+/// bits per second from a launch arg like `-proxichat_bitrate`
+pub fn bitrate_arg(name: &str) -> Option<u32> {
+    match launch_arg(name).map(|bitrate| bitrate.parse::<u32>()) {
+        Some(Ok(bitrate)) => Some(bitrate),
+        Some(Err(err)) => {
+            log::warn!("invalid {name}: {err}");
+            None
+        }
+        None => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bitrate_is_the_lower_of_both_sides() {
+        let capabilities = Capabilities {
+            max_bitrate: 40_000,
+            ..Capabilities::default()
+        };
+        let settings = capabilities
+            .negotiate(&SUPPORTED_CODECS, DEFAULT_BITRATE)
+            .unwrap();
+        assert_eq!(settings.codec, Codec::LowRateAdpcm);
+        assert_eq!(settings.bitrate, 33_000);
+
+        let capabilities = Capabilities {
+            max_bitrate: u32::MAX,
+            ..Capabilities::default()
+        };
+        let settings = capabilities.negotiate(&SUPPORTED_CODECS, 50_000).unwrap();
+        assert_eq!(settings.bitrate, 45_000);
+    }
+
+    #[test]
+    fn codec_follows_the_server() {
+        let capabilities = Capabilities {
+            codecs: vec![Codec::Pcm16, Codec::Adpcm],
+            ..Capabilities::default()
+        };
+        let settings = capabilities
+            .negotiate(&SUPPORTED_CODECS, DEFAULT_BITRATE)
+            .unwrap();
+        assert_eq!(settings.codec, Codec::Adpcm);
+        assert_eq!(settings.bitrate, Codec::Adpcm.bitrates()[0]);
+
+        let capabilities = Capabilities {
+            codecs: Vec::new(),
+            ..Capabilities::default()
+        };
+        assert!(matches!(
+            capabilities.negotiate(&SUPPORTED_CODECS, DEFAULT_BITRATE),
+            Err(ProxiChatError::NoCommonCodec)
+        ));
+    }
+
+    #[test]
+    fn other_sample_rates_and_frame_sizes_are_refused() {
+        let capabilities = Capabilities {
+            sample_rates: vec![WIRE_SAMPLE_RATE / 2],
+            ..Capabilities::default()
+        };
+        assert!(matches!(
+            capabilities.negotiate(&SUPPORTED_CODECS, DEFAULT_BITRATE),
+            Err(ProxiChatError::NoCommonSampleRate(WIRE_SAMPLE_RATE))
+        ));
+
+        let capabilities = Capabilities {
+            frame_sizes: vec![AUDIO_BUFFER_SIZE as u32 * 2],
+            ..Capabilities::default()
+        };
+        assert!(matches!(
+            capabilities.negotiate(&SUPPORTED_CODECS, DEFAULT_BITRATE),
+            Err(ProxiChatError::NoCommonFrameSize(AUDIO_BUFFER_SIZE))
+        ));
+
+        let settings = Capabilities::default()
+            .negotiate(&SUPPORTED_CODECS, DEFAULT_BITRATE)
+            .unwrap();
+        assert_eq!(settings.sample_rate, WIRE_SAMPLE_RATE);
+        assert_eq!(settings.frame_size, AUDIO_BUFFER_SIZE as u32);
+    }
+}
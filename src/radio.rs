@@ -0,0 +1,66 @@
+use fundsp::hacker32::*;
+
+use crate::shared::{AudioSampleType, AudioSampleVec, WIRE_SAMPLE_RATE};
+
+/// the band a cheap radio lets through is roughly 300 hz to 3 khz
+const RADIO_CENTER: f32 = 1000.;
+const RADIO_Q: f32 = 0.7;
+/// how hard the radio is driven into saturation
+const RADIO_DRIVE: f32 = 2.;
+
+/// makes a speaker sound like they are talking through a radio
+pub struct RadioFilter {
+    filter: Box<dyn AudioUnit32>,
+}
+
+impl std::fmt::Debug for RadioFilter {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("RadioFilter").finish_non_exhaustive()
+    }
+}
+
+impl Default for RadioFilter {
+    fn default() -> Self {
+        let mut filter: Box<dyn AudioUnit32> = Box::new(bandpass_hz(RADIO_CENTER, RADIO_Q));
+        filter.reset(Some(WIRE_SAMPLE_RATE as f64));
+
+        Self { filter }
+    }
+}
+
+impl RadioFilter {
+    pub fn process(&mut self, audio: &[AudioSampleType], out: &mut AudioSampleVec) {
+        out.clear();
+        out.extend(audio.iter().map(|sample| {
+            let mut filtered = [0.];
+            self.filter.tick(&[*sample], &mut filtered);
+
+            (filtered[0] * RADIO_DRIVE).tanh() / RADIO_DRIVE.tanh()
+        }));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn radio_rms(frequency: f32) -> f32 {
+        let sine = (0..WIRE_SAMPLE_RATE)
+            .map(|i| {
+                0.1 * (std::f32::consts::TAU * frequency * i as f32 / WIRE_SAMPLE_RATE as f32).sin()
+            })
+            .collect::<Vec<_>>();
+
+        let mut out = AudioSampleVec::new();
+        RadioFilter::default().process(&sine, &mut out);
+
+        // skip the filter settling
+        let settled = &out[out.len() / 2..];
+        (settled.iter().map(|sample| sample * sample).sum::<f32>() / settled.len() as f32).sqrt()
+    }
+
+    #[test]
+    fn low_frequencies_are_cut() {
+        assert!(radio_rms(100.) < 0.25 * radio_rms(1000.));
+    }
+}
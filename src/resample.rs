@@ -0,0 +1,201 @@
+use crate::shared::{AudioSampleType, AudioSampleVec, DEFAULT_FILL_SAMPLE};
+
+/// streaming linear interpolation resampler, plenty for voice
+///
+/// the phase and the last sample are kept between calls so chunks join without clicks
+#[derive(Debug)]
+pub struct Resampler {
+    step: f64,
+    position: f64,
+    last: AudioSampleType,
+}
+
+impl Resampler {
+    pub fn new(from_rate: u32, to_rate: u32) -> Self {
+        Self {
+            step: from_rate.max(1) as f64 / to_rate.max(1) as f64,
+            position: 0.,
+            last: DEFAULT_FILL_SAMPLE,
+        }
+    }
+
+    /// appends the resampled `input` to `out`
+    pub fn process(&mut self, input: &[AudioSampleType], out: &mut AudioSampleVec) {
+        if input.is_empty() {
+            return;
+        }
+
+        // index 0 is the last sample of the previous chunk, the input starts at 1
+        while self.position < input.len() as f64 {
+            let index = self.position.floor() as usize;
+            let fraction = (self.position - index as f64) as AudioSampleType;
+
+            let a = match index {
+                0 => self.last,
+                index => input[index - 1],
+            };
+            let b = input[index];
+
+            out.push(a + (b - a) * fraction);
+            self.position += self.step;
+        }
+
+        self.position -= input.len() as f64;
+        self.last = input[input.len() - 1];
+    }
+}
+
+/// averages interleaved frames into mono samples appended to `out`
+pub fn downmix(data: &[AudioSampleType], channels: usize, out: &mut AudioSampleVec) {
+    let channels = channels.max(1);
+
+    out.extend(
+        data.chunks_exact(channels)
+            .map(|frame| frame.iter().sum::<AudioSampleType>() / channels as AudioSampleType),
+    );
+}
+
+/// appends one channel of interleaved frames to `out`
+pub fn deinterleave(
+    data: &[AudioSampleType],
+    channels: usize,
+    channel: usize,
+    out: &mut AudioSampleVec,
+) {
+    out.extend(data.iter().skip(channel).step_by(channels.max(1)));
+}
+
+/// writes a stereo signal to interleaved device frames
+///
+/// mono devices get both channels averaged and channels past the second are left silent
+pub fn upmix_stereo(
+    left: &[AudioSampleType],
+    right: &[AudioSampleType],
+    channels: usize,
+    data: &mut [AudioSampleType],
+) {
+    for (frame, (left, right)) in data
+        .chunks_exact_mut(channels.max(1))
+        .zip(left.iter().zip(right))
+    {
+        match frame {
+            [mono] => *mono = (left + right) / 2.,
+            [frame_left, frame_right, rest @ ..] => {
+                *frame_left = *left;
+                *frame_right = *right;
+                rest.fill(DEFAULT_FILL_SAMPLE);
+            }
+            [] => {}
+        }
+    }
+}
+
+/// taps of the lowpass for every step of an integer rate change
+const FIR_TAPS_PER_FACTOR: usize = 8;
+
+/// lowers the rate by an integer factor, filtering out what the lower rate can't hold first
+#[derive(Debug)]
+pub struct Decimator {
+    factor: usize,
+    taps: AudioSampleVec,
+    history: AudioSampleVec,
+}
+
+impl Decimator {
+    pub fn new(factor: usize) -> Self {
+        let taps = lowpass_taps(factor);
+
+        Self {
+            factor: factor.max(1),
+            history: vec![DEFAULT_FILL_SAMPLE; taps.len() - 1],
+            taps,
+        }
+    }
+
+    /// appends the decimated `input` to `out`, the filter state is kept between calls
+    pub fn process(&mut self, input: &[AudioSampleType], out: &mut AudioSampleVec) {
+        self.history.extend_from_slice(input);
+        let consumed = convolve(&self.history, &self.taps, self.factor, out);
+        self.history.drain(..consumed);
+    }
+}
+
+/// raises the rate by an integer factor, the lowpass smooths out the inserted samples
+#[derive(Debug)]
+pub struct Interpolator {
+    factor: usize,
+    taps: AudioSampleVec,
+    history: AudioSampleVec,
+}
+
+impl Interpolator {
+    pub fn new(factor: usize) -> Self {
+        let taps = lowpass_taps(factor);
+
+        Self {
+            factor: factor.max(1),
+            history: vec![DEFAULT_FILL_SAMPLE; taps.len() - 1],
+            taps,
+        }
+    }
+
+    /// appends `factor` samples to `out` for every sample of `input`
+    pub fn process(&mut self, input: &[AudioSampleType], out: &mut AudioSampleVec) {
+        for sample in input {
+            // the inserted zeros take away from the level, this makes up for it
+            self.history.push(sample * self.factor as AudioSampleType);
+            self.history
+                .extend(std::iter::repeat_n(DEFAULT_FILL_SAMPLE, self.factor - 1));
+        }
+
+        let consumed = convolve(&self.history, &self.taps, 1, out);
+        self.history.drain(..consumed);
+    }
+}
+
+/// a blackman windowed sinc cutting just under the nyquist frequency of the lower rate
+fn lowpass_taps(factor: usize) -> AudioSampleVec {
+    let factor = factor.max(1);
+    let len = FIR_TAPS_PER_FACTOR * factor;
+    let cutoff = 0.45 / factor as f32;
+    let center = (len - 1) as f32 / 2.;
+
+    let taps = (0..len)
+        .map(|i| {
+            let t = i as f32 - center;
+            let sinc = if t == 0. {
+                2. * cutoff
+            } else {
+                (2. * std::f32::consts::PI * cutoff * t).sin() / (std::f32::consts::PI * t)
+            };
+            let phase = 2. * std::f32::consts::PI * i as f32 / (len - 1) as f32;
+
+            sinc * (0.42 - 0.5 * phase.cos() + 0.08 * (2. * phase).cos())
+        })
+        .collect::<AudioSampleVec>();
+
+    let sum = taps.iter().sum::<f32>();
+    taps.into_iter().map(|tap| tap / sum).collect()
+}
+
+/// filters `samples` every `step` samples into `out`, returns how many samples are done with
+fn convolve(
+    samples: &[AudioSampleType],
+    taps: &[AudioSampleType],
+    step: usize,
+    out: &mut AudioSampleVec,
+) -> usize {
+    let mut start = 0;
+    while start + taps.len() <= samples.len() {
+        out.push(
+            samples[start..start + taps.len()]
+                .iter()
+                .zip(taps)
+                .map(|(sample, tap)| sample * tap)
+                .sum(),
+        );
+        start += step;
+    }
+
+    start
+}
@@ -1,83 +1,296 @@
+use rrplug::prelude::Vector3;
 use std::{
-    io::{self, Read, Write},
-    net::{TcpListener, TcpStream},
-    process::Command,
+    collections::{hash_map::RandomState, HashMap, HashSet},
+    hash::BuildHasher,
+    io,
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr, TcpListener, TcpStream, UdpSocket},
+    time::{Duration, SystemTime},
 };
 
 use crate::{
+    attenuation::Attenuation,
+    auth::AuthToken,
+    bans::BanList,
     bindings::uid_exits,
+    clock::FrameClock,
+    codec::{Codec, VoiceDecoder, VoiceEncoder, SUPPORTED_CODECS},
+    crypto::{Handshake, OpeningKey, SealingKey, Side},
+    framing::{PacketReader, PacketWriter},
+    heartbeat::{idle_timeout, Heartbeat},
+    jitter::JitterBuffer,
+    mixer::{place_for_listener, Listener, Placement, Speaker},
+    protocol::{bitrate_arg, Settings, DEFAULT_BITRATE, PROTOCOL_VERSION},
+    radio::RadioFilter,
     shared::{
-        AudioSampleVec, NetPacket, ProxiChatError, AUDIO_BUFFER_SIZE, DEFAULT_FILL_SAMPLE,
-        PROXICHAT_PORT, READ_BUFFER_SIZE, log_mark_error,
+        launch_arg, log_mark_error, AudioSampleType, AudioSampleVec, NetPacket, ProxiChatError,
+        AUDIO_BUFFER_SIZE, DEFAULT_FILL_SAMPLE, PROXICHAT_PORT, WIRE_SAMPLE_RATE,
+    },
+    voice::{
+        limit_speakers, recv_voice, send_voice, SpeakerAudio, SpeakerPlacement, VoicePacket,
+        VOICE_READ_BUFFER_SIZE,
     },
 };
 
+/// -80 dBFS
+const SILENCE_THRESHOLD: AudioSampleType = 1e-4;
+
 #[allow(clippy::upper_case_acronyms)]
 #[derive(Debug, PartialEq)]
 enum UIDState {
     UID(i64),
     None,
+    /// the keys are agreed on, waiting for the encrypted auth
+    KeyExchanged(i64),
     AuthReady(i64),
 }
 
+/// where a player is, where they are looking and who they play with, fed by the server scripts every frame
+#[derive(Debug, Clone, Copy)]
+pub struct PlayerState {
+    pub origin: Vector3,
+    /// pitch, yaw and roll in degrees
+    pub angles: Vector3,
+    pub team: i32,
+    /// false for dead players and spectators
+    pub alive: bool,
+    /// the player being spectated
+    pub observer_target: Option<i64>,
+}
+
+/// a speaker's audio is encoded once for every codec, bitrate and filter listeners need it in
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+struct StreamKey {
+    codec: Codec,
+    bitrate: u32,
+    radio: bool,
+}
+
 #[derive(Debug)]
 struct ClientConnection {
     stream: TcpStream,
+    /// decoded frames waiting to be mixed, in sequence order
+    received_audio: JitterBuffer,
+    decode_buffer: AudioSampleVec,
     audio_buffer: AudioSampleVec,
-    send_audio_buffer: AudioSampleVec, // AudioNode<Sample = Float<f32>, Inputs = Size<AUDIO_BUFFER_SIZE>, Outputs = Size<AUDIO_BUFFER_SIZE>, Setting = Type>
-    read_buffer: Vec<u8>,
+    /// who the client hears this frame and from where, loudest first
+    placements: Vec<Placement>,
+    /// the client's own audio for everyone who hears it
+    encoders: HashMap<StreamKey, VoiceEncoder>,
+    reader: PacketReader,
+    writer: PacketWriter,
     player_uid: UIDState,
+    heartbeat: Heartbeat,
+    /// the protocol version the client sent first
+    version: Option<u32>,
+    settings: Option<Settings>,
+    decoder: VoiceDecoder,
+    radio: bool,
+    radio_filter: RadioFilter,
+    radio_buffer: AudioSampleVec,
+    session: u64,
+    voice_sealing: Option<SealingKey>,
+    voice_opening: Option<OpeningKey>,
+    voice_addr: Option<SocketAddr>,
+    send_sequence: u32,
+}
+
+impl ClientConnection {
+    fn new(stream: TcpStream, addr: SocketAddr, idle_timeout: Duration) -> Self {
+        Self {
+            stream,
+            received_audio: JitterBuffer::new(WIRE_SAMPLE_RATE),
+            decode_buffer: AudioSampleVec::with_capacity(AUDIO_BUFFER_SIZE),
+            audio_buffer: vec![DEFAULT_FILL_SAMPLE; AUDIO_BUFFER_SIZE],
+            placements: Vec::new(),
+            encoders: HashMap::new(),
+            reader: PacketReader::default(),
+            writer: PacketWriter::default(),
+            player_uid: UIDState::None,
+            heartbeat: Heartbeat::new(idle_timeout),
+            version: None,
+            settings: None,
+            decoder: VoiceDecoder::new(SUPPORTED_CODECS[0]),
+            radio: false,
+            radio_filter: RadioFilter::default(),
+            radio_buffer: AudioSampleVec::with_capacity(AUDIO_BUFFER_SIZE),
+            session: new_session_id(addr),
+            voice_sealing: None,
+            voice_opening: None,
+            voice_addr: None,
+            send_sequence: 0,
+        }
+    }
+
+    /// the uid the client claimed, known from the key exchange on
+    fn uid(&self) -> Option<i64> {
+        match self.player_uid {
+            UIDState::UID(uid) | UIDState::KeyExchanged(uid) | UIDState::AuthReady(uid) => {
+                Some(uid)
+            }
+            UIDState::None => None,
+        }
+    }
 }
 
 #[derive(Debug)]
 pub struct Server {
     server: TcpListener,
+    voice_socket: UdpSocket,
+    voice_read_buffer: Vec<u8>,
+    voice_send_buffer: Vec<u8>,
+    /// this frame's audio of every speaker in every stream someone hears it in
+    encoded_frames: HashMap<(i64, StreamKey), Vec<u8>>,
     connections: Vec<ClientConnection>,
+    players: HashMap<i64, PlayerState>,
+    /// handed out to players in game, a voice client has to know its player's token
+    auth_tokens: HashMap<i64, AuthToken>,
+    preferred_codecs: Vec<Codec>,
+    /// `-proxichat_bitrate`, clients can ask for less
+    bitrate: u32,
+    pub attenuation: Attenuation,
+    muted: HashSet<i64>,
+    bans: BanList,
+    /// `-proxichat_timeout`
+    idle_timeout: Duration,
+    clock: FrameClock,
 }
 
-impl Default for Server {
-    fn default() -> Self {
-        let cmd_result = Command::new("ipconfig")
-            .output()
-            .expect("failed to get ipconfig")
-            .stdout;
-        let cmd_result = String::from_utf8_lossy(&cmd_result).to_string();
-        let addr = cmd_result
-            .split('\n')
-            .filter(|line| line.contains("  IPv4 Address"))
-            .filter_map(|line| line.split(':').nth(1))
-            .map(|addr| addr.trim().trim_end())
-            .last()
-            .expect("couldn't find the machine's ip address");
-
-        let server = TcpListener::bind(format!("{addr}:{PROXICHAT_PORT}"))
-            .expect("failed to bind to address : {addr}");
-
-        server
-            .set_nonblocking(true)
-            .expect("failed to set non_blocking");
+impl Server {
+    /// binds to `-proxichat_bind` or every interface, on `-proxichat_port` or `PROXICHAT_PORT`
+    pub fn new() -> Result<Self, ProxiChatError> {
+        let port = match launch_arg("-proxichat_port").map(|port| port.parse::<u16>()) {
+            Some(Ok(port)) => port,
+            Some(Err(err)) => {
+                log::warn!("invalid -proxichat_port: {err}; using {PROXICHAT_PORT}");
+                PROXICHAT_PORT
+            }
+            None => PROXICHAT_PORT,
+        };
+
+        let (server, voice_socket) = match launch_arg("-proxichat_bind") {
+            Some(ip) => bind(SocketAddr::new(ip.parse()?, port))?,
+            None => bind((Ipv4Addr::UNSPECIFIED, port).into()).or_else(|err| {
+                log::warn!("couldn't bind to ipv4: {err}; trying ipv6");
+                bind((Ipv6Addr::UNSPECIFIED, port).into())
+            })?,
+        };
 
-        Self {
+        log::info!(
+            "proximity chat server listening on {}",
+            server.local_addr()?
+        );
+
+        Ok(Self {
             server,
+            voice_socket,
+            voice_read_buffer: vec![0; VOICE_READ_BUFFER_SIZE],
+            voice_send_buffer: Vec::new(),
+            encoded_frames: HashMap::new(),
             connections: Vec::new(),
+            players: HashMap::new(),
+            auth_tokens: HashMap::new(),
+            preferred_codecs: preferred_codecs(),
+            bitrate: bitrate_arg("-proxichat_bitrate").unwrap_or(DEFAULT_BITRATE),
+            attenuation: Attenuation::default(),
+            muted: HashSet::new(),
+            bans: BanList::load(),
+            idle_timeout: idle_timeout(),
+            clock: FrameClock::default(),
+        })
+    }
+
+    pub fn voice_port(&self) -> Option<u16> {
+        self.server.local_addr().ok().map(|addr| addr.port())
+    }
+
+    pub fn update_player(&mut self, uid: i64, state: PlayerState) {
+        self.players.insert(uid, state);
+    }
+
+    /// the player's token for this game, a new one is made the first time
+    pub fn auth_token(&mut self, uid: i64) -> Result<AuthToken, ProxiChatError> {
+        if let Some(token) = self.auth_tokens.get(&uid) {
+            return Ok(*token);
         }
+
+        let token = AuthToken::generate()?;
+        self.auth_tokens.insert(uid, token);
+        Ok(token)
+    }
+
+    /// also forgets the player's token and drops their voice connection since they left
+    pub fn remove_player(&mut self, uid: i64) {
+        self.players.remove(&uid);
+        self.auth_tokens.remove(&uid);
+        self.drop_connections("you left the game", |conn| conn.uid() == Some(uid));
+    }
+
+    /// the player can still hear everyone but nobody hears them, returns false if already muted
+    pub fn mute(&mut self, uid: i64) -> bool {
+        self.muted.insert(uid)
+    }
+
+    pub fn unmute(&mut self, uid: i64) -> bool {
+        self.muted.remove(&uid)
+    }
+
+    /// drops the player's voice connection, they can connect again by rejoining
+    pub fn kick(&mut self, uid: i64) -> bool {
+        self.drop_connections("you were kicked from voice", |conn| conn.uid() == Some(uid))
+    }
+
+    /// kicks the player and refuses their auth until they are unbanned
+    pub fn ban(&mut self, uid: i64) -> Result<bool, ProxiChatError> {
+        self.drop_connections("you were banned from voice", |conn| conn.uid() == Some(uid));
+        self.bans.ban(uid)
+    }
+
+    /// tells every connection matching `f` why it's dropped, returns true if any were
+    fn drop_connections(
+        &mut self,
+        reason: &str,
+        mut f: impl FnMut(&ClientConnection) -> bool,
+    ) -> bool {
+        let count = self.connections.len();
+        self.connections.retain_mut(|conn| {
+            if !f(conn) {
+                return true;
+            }
+
+            log::info!("dropping the connection of {:?}: {reason}", conn.uid());
+            disconnect(conn, reason);
+            false
+        });
+
+        count != self.connections.len()
+    }
+
+    pub fn unban(&mut self, uid: i64) -> Result<bool, ProxiChatError> {
+        self.bans.unban(uid)
+    }
+
+    fn listener(&self, uid: i64) -> Listener {
+        let state = self.players.get(&uid).copied();
+        let view = match state {
+            Some(PlayerState {
+                alive: false,
+                observer_target: Some(target),
+                ..
+            }) => self.players.get(&target).copied().or(state),
+            _ => state,
+        };
+
+        Listener { uid, state, view }
     }
-}
 
-impl Server {
     /// has to be ran on the tf2 thread aka runframe since it accesses player array
     pub fn run(&mut self) {
         match self.server.accept() {
             Ok((conn, addr)) => match conn.set_nonblocking(true) {
                 Ok(_) => {
                     log::info!("connection created with {addr:?}");
-                    self.connections.push(ClientConnection {
-                        stream: conn,
-                        audio_buffer: vec![DEFAULT_FILL_SAMPLE; AUDIO_BUFFER_SIZE],
-                        send_audio_buffer: vec![DEFAULT_FILL_SAMPLE; AUDIO_BUFFER_SIZE],
-                        read_buffer: vec![0; READ_BUFFER_SIZE],
-                        player_uid: UIDState::None,
-                    })
+                    self.connections
+                        .push(ClientConnection::new(conn, addr, self.idle_timeout))
                 }
                 Err(err) => log::error!("failed to connect to a stream: {err}"),
             },
@@ -87,70 +300,391 @@ impl Server {
             _ => {}
         }
 
-        if let Some((i, err)) = self
-            .connections
-            .iter_mut()
-            .enumerate()
-            .map_while(|(i, conn)| Some((i, handle_collecting_packets(conn).err()?)))
-            .filter(|(_, err)| !err.is_would_block_error())
-            .last()
+        self.connections.retain_mut(|conn| {
+            match handle_collecting_packets(
+                conn,
+                &self.auth_tokens,
+                &self.preferred_codecs,
+                self.bitrate,
+                &self.bans,
+                uid_exits,
+            ) {
+                Err(ProxiChatError::Disconnected(reason)) => {
+                    log::info!("{:?} disconnected: {reason}", conn.uid());
+                    false
+                }
+                Err(err) if !err.is_would_block_error() => {
+                    log::error!("receiving: {err}");
+                    log::info!("terminating the connection");
+                    reject(conn, &err);
+                    false
+                }
+                _ => true,
+            }
+        });
+
+        // half open connections would stay around forever otherwise
+        self.drop_connections("timed out", |conn| conn.heartbeat.timed_out());
+        self.drop_connections("you aren't on the server anymore", |conn| {
+            conn.uid().is_some_and(|uid| !uid_exits(uid))
+        });
+
+        if let Err(err) = self.handle_collecting_voice() {
+            log::error!("receiving voice: {err}");
+        }
+
+        self.connections
+            .retain_mut(|conn| match handle_sending_packets(conn) {
+                Err(err) if !err.is_would_block_error() => {
+                    log::error!("sending: {err}");
+                    log::info!("terminating the connection");
+                    false
+                }
+                _ => true,
+            });
+
+        // a tick is a lot longer than a frame so every frame since the last one is sent now
+        for _ in 0..self.clock.due() {
+            self.mix_audio();
+
+            if let Err(err) = self.handle_sending_voice() {
+                log::error!("sending voice: {err}");
+            }
+        }
+    }
+
+    fn handle_collecting_voice(&mut self) -> Result<(), ProxiChatError> {
+        while let Some((datagram, addr)) =
+            recv_voice(&self.voice_socket, &mut self.voice_read_buffer)?
         {
-            log::error!("receiving: {err}");
-            log::info!("terminating the connection");
-            self.connections.remove(i);
+            let Some(client) = self.connections.iter_mut().find(|c| {
+                c.session == datagram.session && matches!(c.player_uid, UIDState::UID(_))
+            }) else {
+                continue;
+            };
+            let Some(key) = client.voice_opening.as_mut() else {
+                continue;
+            };
+
+            let packet = match datagram.open(key) {
+                Ok(packet) => packet,
+                // a late or repeated datagram
+                Err(ProxiChatError::ReplayedPacket(_)) => continue,
+                Err(err) => {
+                    log::warn!("dropping a voice packet from {addr}: {err}");
+                    continue;
+                }
+            };
+
+            if let VoicePacket::ProccessedAudio { .. } = packet {
+                log::warn!("{addr}: {}", ProxiChatError::ImpossibleOnServer);
+                continue;
+            }
+
+            // only now since anyone could send a datagram with someone else's session
+            client.voice_addr = Some(addr);
+
+            if let VoicePacket::NewAudio {
+                sequence,
+                radio,
+                audio,
+                ..
+            } = packet
+            {
+                // a missing frame is concealed by the jitter buffer
+                if let Err(err) = client.decoder.decode(&audio, &mut client.decode_buffer) {
+                    log::warn!("dropping voice frame: {err}");
+                    continue;
+                }
+
+                client.radio = radio;
+                client
+                    .received_audio
+                    .push(sequence, client.decode_buffer.clone());
+            }
         }
 
-        // TODO: proccess audio
+        Ok(())
+    }
 
-        self.connections
-            .iter_mut()
-            .for_each(|c| c.send_audio_buffer.clear());
-        self.connections
-            .iter_mut()
-            .for_each(|c| c.send_audio_buffer.extend_from_slice(&c.audio_buffer));
+    fn handle_sending_voice(&mut self) -> Result<(), ProxiChatError> {
+        self.encode_speakers();
+
+        for client in self.connections.iter_mut() {
+            let (Some(addr), Some(key), Some(settings), UIDState::UID(_)) = (
+                client.voice_addr,
+                client.voice_sealing.as_mut(),
+                client.settings,
+                &client.player_uid,
+            ) else {
+                continue;
+            };
+
+            // the client's jitter buffer counts skipped frames as time passing
+            let sequence = client.send_sequence;
+            client.send_sequence = client.send_sequence.wrapping_add(1);
+
+            let mut speakers = client
+                .placements
+                .iter()
+                .filter_map(|placement| {
+                    let stream = StreamKey {
+                        codec: settings.codec,
+                        bitrate: settings.bitrate,
+                        radio: placement.radio,
+                    };
+
+                    Some(SpeakerAudio {
+                        uid: placement.uid,
+                        placement: SpeakerPlacement::new(placement.gain, placement.direction),
+                        audio: self.encoded_frames.get(&(placement.uid, stream))?.clone(),
+                    })
+                })
+                .collect::<Vec<SpeakerAudio>>();
+            limit_speakers(&mut speakers);
+
+            // nobody to hear, nothing to send
+            if speakers.is_empty() {
+                continue;
+            }
+
+            let packet = VoicePacket::ProccessedAudio { sequence, speakers };
 
-        if let Some((i, err)) = self
+            send_voice(
+                &self.voice_socket,
+                Some(addr),
+                client.session,
+                &packet,
+                key,
+                &mut self.voice_send_buffer,
+            )?;
+        }
+
+        Ok(())
+    }
+
+    /// encodes every speaker once for each stream somebody hears them in
+    fn encode_speakers(&mut self) {
+        let streams = self
             .connections
-            .iter_mut()
-            .enumerate()
-            .map_while(|(i, conn)| Some((i, handle_sending_packets(conn).err()?)))
-            .filter(|(_, err)| !err.is_would_block_error())
-            .last()
-        {
-            log::error!("sending: {err}");
-            log::info!("terminating the connection");
-            self.connections.remove(i);
+            .iter()
+            .filter_map(|listener| Some((listener.settings?, &listener.placements)))
+            .flat_map(|(settings, placements)| {
+                placements.iter().map(move |placement| {
+                    (
+                        placement.uid,
+                        StreamKey {
+                            codec: settings.codec,
+                            bitrate: settings.bitrate,
+                            radio: placement.radio,
+                        },
+                    )
+                })
+            })
+            .collect::<HashSet<(i64, StreamKey)>>();
+
+        self.encoded_frames.clear();
+        for conn in self.connections.iter_mut() {
+            let UIDState::UID(uid) = conn.player_uid else {
+                continue;
+            };
+            conn.encoders
+                .retain(|stream, _| streams.contains(&(uid, *stream)));
+
+            for stream in streams
+                .iter()
+                .filter(|(speaker, _)| *speaker == uid)
+                .map(|(_, stream)| *stream)
+            {
+                let audio = if stream.radio {
+                    &conn.radio_buffer
+                } else {
+                    &conn.audio_buffer
+                };
+
+                let mut frame = Vec::new();
+                conn.encoders
+                    .entry(stream)
+                    .or_insert_with(|| VoiceEncoder::new(stream.codec, stream.bitrate))
+                    .encode(audio, &mut frame);
+                self.encoded_frames.insert((uid, stream), frame);
+            }
         }
     }
+
+    /// decides who every connection hears this frame
+    fn mix_audio(&mut self) {
+        for conn in self.connections.iter_mut() {
+            // plays silence once a speaker stopped sending
+            conn.received_audio.fill(&mut conn.audio_buffer);
+        }
+
+        for conn in self.connections.iter_mut().filter(|c| c.radio) {
+            conn.radio_filter
+                .process(&conn.audio_buffer, &mut conn.radio_buffer);
+        }
+
+        let speakers = self
+            .connections
+            .iter()
+            .filter_map(|c| match c.player_uid {
+                UIDState::UID(uid) if !self.muted.contains(&uid) => Some(Speaker {
+                    uid,
+                    position: self.players.get(&uid).map(|player| player.origin),
+                    team: self.players.get(&uid).map(|player| player.team),
+                    alive: self
+                        .players
+                        .get(&uid)
+                        .map(|player| player.alive)
+                        .unwrap_or(true),
+                    radio: c.radio,
+                }),
+                _ => None,
+            })
+            .collect::<Vec<Speaker>>();
+
+        let placements = self
+            .connections
+            .iter()
+            .map(|conn| match conn.player_uid {
+                UIDState::UID(uid) => {
+                    let mut placements =
+                        place_for_listener(self.listener(uid), &speakers, &self.attenuation);
+                    placements.retain(|placement| !self.is_silent(placement));
+                    placements
+                }
+                _ => Vec::new(),
+            })
+            .collect::<Vec<Vec<Placement>>>();
+
+        for (conn, placements) in self.connections.iter_mut().zip(placements) {
+            conn.placements = placements;
+        }
+    }
+
+    /// a speaker whose audio is this quiet for the listener isn't worth sending
+    fn is_silent(&self, placement: &Placement) -> bool {
+        let Some(speaker) = self
+            .connections
+            .iter()
+            .find(|conn| conn.player_uid == UIDState::UID(placement.uid))
+        else {
+            return true;
+        };
+        let audio = if placement.radio {
+            &speaker.radio_buffer
+        } else {
+            &speaker.audio_buffer
+        };
+
+        audio
+            .iter()
+            .all(|sample| (sample * placement.gain).abs() < SILENCE_THRESHOLD)
+    }
 }
 
-fn handle_collecting_packets(client: &mut ClientConnection) -> Result<(), ProxiChatError> {
-    client.read_buffer.clear();
+fn handle_collecting_packets(
+    client: &mut ClientConnection,
+    auth_tokens: &HashMap<i64, AuthToken>,
+    preferred_codecs: &[Codec],
+    bitrate: u32,
+    bans: &BanList,
+    uid_exists: impl Fn(i64) -> bool,
+) -> Result<(), ProxiChatError> {
+    client
+        .reader
+        .fill(&mut client.stream)
+        .map_err(|err| log_mark_error("read sv", err))?;
 
-    if client.stream.read(&mut client.read_buffer).map_err(|err| log_mark_error("read sv", err))? > READ_BUFFER_SIZE {
-        log::warn!("incorrect amount of bytes received!");
-        return Ok(());
+    while let Some(packet) = client
+        .reader
+        .next_packet()
+        .map_err(|err| log_mark_error("deserialize sv", err))?
+    {
+        client.heartbeat.received(&packet);
+        handle_packet(
+            client,
+            packet,
+            auth_tokens,
+            preferred_codecs,
+            bitrate,
+            bans,
+            &uid_exists,
+        )?;
     }
 
-    let packet: NetPacket = bincode::deserialize(&client.read_buffer).map_err(|err| log_mark_error("deserialize sv", err))?;
-    // let packet: NetPacket = match bincode::deserialize(&client.read_buffer).map_err(|err| log_mark_error("deserialize sv", err)) {
-    //     Ok(p) => p,
-    //     Err(_) => return Ok(()), // ignore all bincode on decode since it can get garbage data by accident
-    // };
+    Ok(())
+}
 
+fn handle_packet(
+    client: &mut ClientConnection,
+    packet: NetPacket,
+    auth_tokens: &HashMap<i64, AuthToken>,
+    preferred_codecs: &[Codec],
+    bitrate: u32,
+    bans: &BanList,
+    uid_exists: impl Fn(i64) -> bool,
+) -> Result<(), ProxiChatError> {
     match packet {
-        NetPacket::Auth { uid } => {
-            if uid_exits(uid) {
-                log::info!("auth completed with client");
-                client.player_uid = UIDState::AuthReady(uid)
-            } else {
+        NetPacket::Version { version } => {
+            if client.version.is_some() {
+                Err(ProxiChatError::ImpossibleOnServer)?
+            }
+
+            if version != PROTOCOL_VERSION {
+                Err(ProxiChatError::UnsupportedVersion(version))?
+            }
+
+            client.version = Some(version);
+        }
+        NetPacket::KeyExchange { uid, public_key } => {
+            if client.player_uid != UIDState::None {
+                Err(ProxiChatError::ImpossibleOnServer)?
+            }
+
+            if client.version.is_none() {
+                Err(ProxiChatError::MissingVersion)?
+            }
+
+            if !uid_exists(uid) {
                 Err(ProxiChatError::InvalidUID(uid))?
             }
+
+            if bans.contains(uid) {
+                Err(ProxiChatError::Banned(uid))?
+            }
+
+            let token = *auth_tokens
+                .get(&uid)
+                .ok_or(ProxiChatError::InvalidAuthToken(uid))?;
+
+            let handshake = Handshake::default();
+            client.writer.queue(&NetPacket::KeyExchangeReply {
+                public_key: handshake.public_key(),
+            })?;
+
+            // a client without the right token can't decrypt or send anything from here on
+            let keys = handshake.finish(Side::Server, public_key, token)?;
+            client.reader.set_key(keys.stream_opening);
+            client.writer.set_key(keys.stream_sealing);
+            client.voice_sealing = Some(keys.voice_sealing);
+            client.voice_opening = Some(keys.voice_opening);
+            client.player_uid = UIDState::KeyExchanged(uid);
+        }
+        NetPacket::Auth { capabilities } => {
+            let UIDState::KeyExchanged(uid) = client.player_uid else {
+                Err(ProxiChatError::AuthBeforeKeyExchange)?
+            };
+
+            let settings = capabilities.negotiate(preferred_codecs, bitrate)?;
+
+            log::info!("auth completed with client; using {settings:?}");
+            client.decoder = VoiceDecoder::new(settings.codec);
+            client.settings = Some(settings);
+            client.player_uid = UIDState::AuthReady(uid)
         }
-        NetPacket::NewAudio(audio) => audio
-            .into_iter()
-            .enumerate()
-            .for_each(|(i, e)| client.audio_buffer[i] = e), // not sure if there is a better way; perhaps should be change later
+        NetPacket::Ping | NetPacket::Pong => {}
+        NetPacket::Disconnect { reason } => Err(ProxiChatError::Disconnected(reason))?,
         _ => Err(ProxiChatError::ImpossibleOnServer)?,
     }
 
@@ -158,32 +692,298 @@ fn handle_collecting_packets(client: &mut ClientConnection) -> Result<(), ProxiC
 }
 
 fn handle_sending_packets(client: &mut ClientConnection) -> Result<(), ProxiChatError> {
-    let packet = if let UIDState::AuthReady(uid) = client.player_uid {
-        client.player_uid = UIDState::UID(uid);
-        NetPacket::AuthComfirm
-    } else if client.player_uid != UIDState::None {
-        let mut buf: AudioSampleVec = vec![DEFAULT_FILL_SAMPLE; AUDIO_BUFFER_SIZE]; // can't avoid 2 or 1 clones/moves
-        client.send_audio_buffer.clone_into(&mut buf);
-        NetPacket::ProccessedAudio(
-            buf.try_into()
-                .map_err(|_| ProxiChatError::VecToArrayError)?,
-        )
-    } else {
-        NetPacket::None
-    };
+    // a client of another version could only read a reject
+    if client.version.is_none() {
+        return client.writer.flush(&mut client.stream);
+    }
+
+    match client.player_uid {
+        UIDState::AuthReady(uid) => {
+            client.player_uid = UIDState::UID(uid);
+            if let Some(settings) = client.settings {
+                client.writer.queue(&NetPacket::AuthComfirm {
+                    session: client.session,
+                    settings,
+                })?;
+            }
+        }
+        UIDState::None => client.writer.queue(&NetPacket::None)?,
+        UIDState::UID(_) | UIDState::KeyExchanged(_) => {}
+    }
 
-    let buf = bincode::serialize(&packet)?;
-    log::info!(
-        "sending buffer of size {} when max size is {}",
-        buf.len(),
-        READ_BUFFER_SIZE
-    );
+    // the client decrypts everything after the key exchange reply
+    if client.player_uid != UIDState::None {
+        client.heartbeat.queue(&mut client.writer)?;
+    }
 
-    client.stream.write_all(&buf)?;
-    // let mut size_to_write = buf.len() as i32;
-    // while size_to_write <= 0 {
-    //     size_to_write -= client.stream.write(&buf)? as i32;
-    // }
+    client.writer.flush(&mut client.stream)?;
 
     Ok(())
 }
+
+/// tells the client why it's dropped, the connection may already be gone so errors are ignored
+fn reject(client: &mut ClientConnection, err: &ProxiChatError) {
+    let reject = NetPacket::Reject {
+        reason: err.to_string(),
+    };
+
+    if client.writer.queue(&reject).is_ok() {
+        _ = client.writer.flush(&mut client.stream);
+    }
+}
+
+/// lets the client know it shouldn't try to reconnect on its own, errors are ignored too
+fn disconnect(client: &mut ClientConnection, reason: &str) {
+    if client.version.is_none() {
+        return;
+    }
+
+    let disconnect = NetPacket::Disconnect {
+        reason: reason.to_string(),
+    };
+
+    if client.writer.queue(&disconnect).is_ok() {
+        _ = client.writer.flush(&mut client.stream);
+    }
+}
+
+/// only used to match voice datagrams to their tcp connection
+fn new_session_id(addr: SocketAddr) -> u64 {
+    RandomState::new().hash_one((addr, SystemTime::now()))
+}
+
+/// `-proxichat_codec <codec>` moves that codec to the front of the list
+fn preferred_codecs() -> Vec<Codec> {
+    let mut codecs = SUPPORTED_CODECS.to_vec();
+
+    match launch_arg("-proxichat_codec").map(|codec| codec.parse::<Codec>()) {
+        Some(Ok(codec)) => codecs.sort_by_key(|c| *c != codec),
+        Some(Err(err)) => log::warn!("{err}; using the default codecs"),
+        None => {}
+    }
+
+    codecs
+}
+
+/// the tcp listener and the voice socket share the same address
+fn bind(addr: SocketAddr) -> Result<(TcpListener, UdpSocket), ProxiChatError> {
+    let server = TcpListener::bind(addr)?;
+    server.set_nonblocking(true)?;
+
+    let voice_socket = UdpSocket::bind(addr)?;
+    voice_socket.set_nonblocking(true)?;
+
+    Ok((server, voice_socket))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Instant;
+
+    use super::*;
+    use crate::{
+        client::{handle_receiving, handle_sending, AuthState, VoiceState},
+        protocol::Features,
+    };
+
+    const UID: i64 = 1000;
+
+    fn connect() -> (TcpStream, ClientConnection) {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (server, addr) = listener.accept().unwrap();
+        client.set_nonblocking(true).unwrap();
+        server.set_nonblocking(true).unwrap();
+
+        (
+            client,
+            ClientConnection::new(server, addr, Duration::from_secs(10)),
+        )
+    }
+
+    #[test]
+    fn handshake_completes() {
+        let (mut stream, mut conn) = connect();
+        let token = AuthToken::generate().unwrap();
+        let auth_tokens = HashMap::from([(UID, token)]);
+        let bans = BanList::load();
+
+        let mut reader = PacketReader::default();
+        let mut writer = PacketWriter::default();
+        let mut auth = AuthState::default();
+        let mut heartbeat = Heartbeat::new(Duration::from_secs(10));
+        let mut session = None;
+        let mut features = Features::NONE;
+        let mut voice = VoiceState::default();
+
+        let start = Instant::now();
+        while session.is_none() {
+            assert!(
+                start.elapsed() < Duration::from_secs(5),
+                "handshake stalled"
+            );
+
+            handle_sending(
+                &mut stream,
+                &mut writer,
+                &mut auth,
+                &mut heartbeat,
+                Some(token),
+                UID,
+            )
+            .unwrap();
+            handle_collecting_packets(
+                &mut conn,
+                &auth_tokens,
+                &SUPPORTED_CODECS,
+                DEFAULT_BITRATE,
+                &bans,
+                |uid| uid == UID,
+            )
+            .unwrap();
+            handle_sending_packets(&mut conn).unwrap();
+            handle_receiving(
+                &mut stream,
+                &mut reader,
+                &mut auth,
+                &mut heartbeat,
+                &mut session,
+                &mut features,
+                &mut voice,
+            )
+            .unwrap();
+        }
+
+        assert_eq!(conn.player_uid, UIDState::UID(UID));
+        assert_eq!(session, Some(conn.session));
+        assert_eq!(features, Features::ALL);
+    }
+
+    #[test]
+    fn banned_uid_is_rejected() {
+        let (mut stream, mut conn) = connect();
+        let token = AuthToken::generate().unwrap();
+        let auth_tokens = HashMap::from([(UID, token)]);
+
+        let path = std::env::temp_dir().join("proxichat_bans_server_test.txt");
+        _ = std::fs::remove_file(&path);
+        let mut bans = BanList::load_from(path.clone());
+        bans.ban(UID).unwrap();
+
+        let mut writer = PacketWriter::default();
+        let mut auth = AuthState::default();
+        let mut heartbeat = Heartbeat::new(Duration::from_secs(10));
+
+        let start = Instant::now();
+        let err = loop {
+            assert!(
+                start.elapsed() < Duration::from_secs(5),
+                "banned uid went through"
+            );
+
+            handle_sending(
+                &mut stream,
+                &mut writer,
+                &mut auth,
+                &mut heartbeat,
+                Some(token),
+                UID,
+            )
+            .unwrap();
+            if let Err(err) = handle_collecting_packets(
+                &mut conn,
+                &auth_tokens,
+                &SUPPORTED_CODECS,
+                DEFAULT_BITRATE,
+                &bans,
+                |uid| uid == UID,
+            ) {
+                break err;
+            }
+        };
+        _ = std::fs::remove_file(&path);
+
+        assert!(matches!(err, ProxiChatError::Banned(uid) if uid == UID));
+        assert_ne!(conn.player_uid, UIDState::UID(UID));
+    }
+
+    #[test]
+    fn wrong_token_is_rejected() {
+        let (mut stream, mut conn) = connect();
+        let auth_tokens = HashMap::from([(UID, AuthToken::generate().unwrap())]);
+        let bans = BanList::load();
+
+        let mut writer = PacketWriter::default();
+        let mut auth = AuthState::default();
+        let mut heartbeat = Heartbeat::new(Duration::from_secs(10));
+        let mut reader = PacketReader::default();
+        let mut session = None;
+        let mut features = Features::NONE;
+        let mut voice = VoiceState::default();
+
+        let start = Instant::now();
+        let err = loop {
+            assert!(
+                start.elapsed() < Duration::from_secs(5),
+                "wrong token went through"
+            );
+
+            handle_sending(
+                &mut stream,
+                &mut writer,
+                &mut auth,
+                &mut heartbeat,
+                Some(AuthToken::generate().unwrap()),
+                UID,
+            )
+            .unwrap();
+            if let Err(err) = handle_collecting_packets(
+                &mut conn,
+                &auth_tokens,
+                &SUPPORTED_CODECS,
+                DEFAULT_BITRATE,
+                &bans,
+                |uid| uid == UID,
+            ) {
+                break err;
+            }
+            handle_sending_packets(&mut conn).unwrap();
+            _ = handle_receiving(
+                &mut stream,
+                &mut reader,
+                &mut auth,
+                &mut heartbeat,
+                &mut session,
+                &mut features,
+                &mut voice,
+            );
+        };
+
+        assert!(matches!(err, ProxiChatError::DecryptionFailed));
+        assert_ne!(conn.player_uid, UIDState::UID(UID));
+
+        // the client can't read the reject but knows what it means
+        reject(&mut conn, &err);
+        let start = Instant::now();
+        let err = loop {
+            assert!(
+                start.elapsed() < Duration::from_secs(5),
+                "the client never noticed"
+            );
+
+            match handle_receiving(
+                &mut stream,
+                &mut reader,
+                &mut auth,
+                &mut heartbeat,
+                &mut session,
+                &mut features,
+                &mut voice,
+            ) {
+                Err(err) if !err.is_would_block_error() => break err,
+                _ => {}
+            }
+        };
+        assert!(matches!(err, ProxiChatError::AuthTokenRejected));
+    }
+}
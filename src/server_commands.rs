@@ -0,0 +1,122 @@
+use rrplug::{
+    bindings::command::CCommand,
+    prelude::{CCommandResult, EngineData},
+};
+
+use crate::{attenuation::Attenuation, exports::PLUGIN, server::Server};
+
+type ConCommandCallback = unsafe extern "C" fn(*const CCommand);
+
+pub fn register_server_concommands(engine: &EngineData) {
+    let commands: [(&str, ConCommandCallback, &str); 6] = [
+        (
+            "proxichat_attenuation",
+            attenuation_command,
+            "usage: proxichat_attenuation <linear|inverse|inverse_square|logarithmic> <min distance> <max distance>",
+        ),
+        ("proxichat_mute", mute_command, "usage: proxichat_mute <uid>"),
+        ("proxichat_unmute", unmute_command, "usage: proxichat_unmute <uid>"),
+        ("proxichat_kick", kick_command, "usage: proxichat_kick <uid>"),
+        ("proxichat_ban", ban_command, "usage: proxichat_ban <uid>"),
+        ("proxichat_unban", unban_command, "usage: proxichat_unban <uid>"),
+    ];
+
+    for (name, callback, help) in commands {
+        if let Err(err) = engine.register_concommand(name, callback, help, 0) {
+            log::error!("couldn't register {name}: {err:?}");
+        }
+    }
+}
+
+/// prints the current attenuation without args
+unsafe extern "C" fn attenuation_command(ccommand: *const CCommand) {
+    let parsed_ccommand = CCommandResult::new(ccommand);
+    let proximity_chat = &PLUGIN.wait().proximity_chat;
+
+    let args = parsed_ccommand.get_args();
+    if args.is_empty() {
+        return proximity_chat
+            .with_server(|server| log::info!("attenuation is {}", server.attenuation));
+    }
+
+    proximity_chat.with_server(|server| match parse_attenuation(args, server.attenuation) {
+        Ok(attenuation) => {
+            server.attenuation = attenuation;
+            log::info!("attenuation set to {attenuation}");
+        }
+        Err(err) => log::error!("{err}"),
+    });
+}
+
+/// missing distances are kept from `current`
+fn parse_attenuation(args: &[String], current: Attenuation) -> Result<Attenuation, String> {
+    let parse_distance = |arg: Option<&String>, default: f32| match arg {
+        Some(arg) => arg
+            .parse::<f32>()
+            .map_err(|err| format!("invalid distance {arg}: {err}")),
+        None => Ok(default),
+    };
+
+    Attenuation::new(
+        args[0].parse()?,
+        parse_distance(args.get(1), current.min_distance)?,
+        parse_distance(args.get(2), current.max_distance)?,
+    )
+}
+
+unsafe extern "C" fn mute_command(ccommand: *const CCommand) {
+    with_uid_arg(ccommand, |server, uid| match server.mute(uid) {
+        true => log::info!("muted {uid}"),
+        false => log::info!("{uid} was already muted"),
+    })
+}
+
+unsafe extern "C" fn unmute_command(ccommand: *const CCommand) {
+    with_uid_arg(ccommand, |server, uid| match server.unmute(uid) {
+        true => log::info!("unmuted {uid}"),
+        false => log::info!("{uid} wasn't muted"),
+    })
+}
+
+unsafe extern "C" fn kick_command(ccommand: *const CCommand) {
+    with_uid_arg(ccommand, |server, uid| match server.kick(uid) {
+        true => log::info!("kicked {uid} from voice"),
+        false => log::info!("{uid} isn't connected to voice"),
+    })
+}
+
+unsafe extern "C" fn ban_command(ccommand: *const CCommand) {
+    with_uid_arg(ccommand, |server, uid| match server.ban(uid) {
+        Ok(true) => log::info!("banned {uid} from voice"),
+        Ok(false) => log::info!("{uid} was already banned"),
+        Err(err) => log::error!("couldn't save the ban of {uid}: {err}"),
+    })
+}
+
+unsafe extern "C" fn unban_command(ccommand: *const CCommand) {
+    with_uid_arg(ccommand, |server, uid| match server.unban(uid) {
+        Ok(true) => log::info!("unbanned {uid}"),
+        Ok(false) => log::info!("{uid} wasn't banned"),
+        Err(err) => log::error!("couldn't save the unban of {uid}: {err}"),
+    })
+}
+
+/// runs `f` with the uid given as the first arg of the command
+fn with_uid_arg(ccommand: *const CCommand, f: impl FnOnce(&mut Server, i64)) {
+    let parsed_ccommand = CCommandResult::new(ccommand);
+
+    let uid = match parsed_ccommand
+        .get_args()
+        .first()
+        .map(|arg| arg.parse::<i64>())
+    {
+        Some(Ok(uid)) => uid,
+        Some(Err(err)) => return log::error!("invalid uid: {err}"),
+        None => return log::error!("{} needs a uid", parsed_ccommand.get_command()),
+    };
+
+    PLUGIN
+        .wait()
+        .proximity_chat
+        .with_server(|server| f(server, uid));
+}
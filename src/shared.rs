@@ -1,22 +1,31 @@
 use parking_lot::Mutex;
 use serde::{Deserialize, Serialize};
-use serde_big_array::BigArray;
-use std::mem::size_of;
+use std::{env, path::PathBuf};
 use thiserror::Error;
 
-use crate::{client::Client, server::Server};
+use crate::{
+    client::Client,
+    crypto::PublicKeyBytes,
+    protocol::{Capabilities, Settings, PROTOCOL_VERSION},
+    server::Server,
+};
 
-pub const PROXICHAT_PORT: usize = 8081;
+pub const PROXICHAT_PORT: u16 = 8081;
 pub const AUDIO_BUFFER_SIZE: usize = 128;
-pub const READ_BUFFER_SIZE: usize = size_of::<NetPacket>();
+/// everything on the wire is at this sample rate, devices get converted to and from it
+pub const WIRE_SAMPLE_RATE: u32 = 48000;
+/// the server sends every listener their own interleaved stereo mix
+pub const OUTPUT_CHANNELS: usize = 2;
+pub const READ_BUFFER_SIZE: usize = 1024;
 pub const DEFAULT_FILL_SAMPLE: AudioSampleType = 0.;
 pub type AudioSampleType = f32;
 pub type AudioSampleVec = Vec<AudioSampleType>;
 
 #[derive(Debug)]
 pub enum ProximityChatType {
-    Server(Mutex<Server>),
-    Client(Mutex<Client>),
+    /// `None` when the server couldn't be started
+    Server(Mutex<Option<Box<Server>>>),
+    Client(Mutex<Box<Client>>),
 }
 
 impl ProximityChatType {
@@ -24,9 +33,22 @@ impl ProximityChatType {
         matches!(self, Self::Server(_))
     }
 
+    /// does nothing on clients or if the server couldn't start
+    pub fn with_server(&self, f: impl FnOnce(&mut Server)) {
+        if let ProximityChatType::Server(server) = self {
+            if let Some(server) = server.lock().as_mut() {
+                f(server)
+            }
+        }
+    }
+
     pub fn run(&self) {
         match self {
-            ProximityChatType::Server(s) => s.lock().run(),
+            ProximityChatType::Server(s) => {
+                if let Some(server) = s.lock().as_mut() {
+                    server.run()
+                }
+            }
             ProximityChatType::Client(_) => {},
         }
     }
@@ -42,23 +64,55 @@ impl ProximityChatType {
 impl From<bool> for ProximityChatType {
     fn from(is_server: bool) -> Self {
         if is_server {
-            Self::Server(Mutex::new(Server::default()))
+            let server = Server::new()
+                .map_err(|err| log::error!("couldn't start the proximity chat server: {err}"))
+                .ok()
+                .map(Box::new);
+
+            Self::Server(Mutex::new(server))
         } else {
-            Self::Client(Mutex::new(Client::default()))
+            Self::Client(Mutex::new(Box::default()))
         }
     }
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub enum NetPacket {
-    Auth {
+    /// the first packet a client sends
+    ///
+    /// this and `Reject` must stay the first variants and never change so that every version
+    /// can at least tell the other side why they can't talk
+    Version {
+        version: u32,
+    },
+    /// the server refusing the client, it closes the connection after this
+    Reject {
+        reason: String,
+    },
+    /// sent in the clear like the packets above, everything after them is encrypted
+    ///
+    /// the keys depend on the auth token the server gave `uid` in game
+    KeyExchange {
         uid: i64,
+        public_key: PublicKeyBytes,
+    },
+    KeyExchangeReply {
+        public_key: PublicKeyBytes,
+    },
+    Auth {
+        capabilities: Capabilities,
+    },
+    AuthComfirm {
+        session: u64,
+        settings: Settings,
+    },
+    /// answered with `Pong`, both sides send them to notice a dead connection
+    Ping,
+    Pong,
+    /// the sender is closing the connection
+    Disconnect {
+        reason: String,
     },
-    AuthComfirm,
-    #[serde(with = "BigArray")]
-    NewAudio([AudioSampleType; AUDIO_BUFFER_SIZE]),
-    #[serde(with = "BigArray")]
-    ProccessedAudio([AudioSampleType; AUDIO_BUFFER_SIZE]),
     None,
 }
 
@@ -73,17 +127,92 @@ pub enum ProxiChatError {
     #[error("a client tried to connect with a invalid uid: {0}")]
     InvalidUID(i64),
 
-    #[error("a vec wasn't converted to an array")]
-    VecToArrayError,
+    #[error("a client tried to connect as {0} with the wrong auth token")]
+    InvalidAuthToken(i64),
+
+    #[error(
+        "the client speaks protocol version {0} but the server speaks {}",
+        PROTOCOL_VERSION
+    )]
+    UnsupportedVersion(u32),
+
+    #[error("a client didn't send its protocol version first")]
+    MissingVersion,
+
+    #[error("the server rejected the connection: {0}")]
+    Rejected(String),
+
+    #[error("the other side disconnected: {0}")]
+    Disconnected(String),
+
+    #[error("the server didn't accept the auth token, rejoining the server gets a new one")]
+    AuthTokenRejected,
+
+    #[error("a client tried to auth before the key exchange")]
+    AuthBeforeKeyExchange,
+
+    #[error("the key exchange didn't produce a usable key")]
+    KeyExchangeFailed,
+
+    #[error("a packet couldn't be encrypted")]
+    EncryptionFailed,
+
+    #[error("a packet couldn't be decrypted, it was tampered with or the keys don't match")]
+    DecryptionFailed,
+
+    #[error("packet {0} was already received")]
+    ReplayedPacket(u64),
+
+    #[error("{0} is banned from voice")]
+    Banned(i64),
+
+    #[error("a packet of {0} bytes is too large to be framed")]
+    FrameTooLarge(usize),
+
+    #[error("a voice frame of {0} bytes couldn't be decoded")]
+    InvalidVoiceFrame(usize),
+
+    #[error("the client doesn't support any of the server's codecs")]
+    NoCommonCodec,
+
+    #[error("the client doesn't support the server's sample rate of {0} hz")]
+    NoCommonSampleRate(u32),
+
+    #[error("the client doesn't support the server's frame size of {0} samples")]
+    NoCommonFrameSize(usize),
+
+    #[error("{0} isn't a valid volume")]
+    InvalidVolume(f32),
+
+    #[error("no {0} device available")]
+    NoAudioDevice(&'static str),
+
+    #[error("the {0} sample format isn't supported")]
+    UnsupportedSampleFormat(cpal::SampleFormat),
 
     #[error(transparent)]
     SocketError(#[from] std::io::Error),
 
+    #[error(transparent)]
+    DefaultStreamConfigError(#[from] cpal::DefaultStreamConfigError),
+
+    #[error(transparent)]
+    BuildStreamError(#[from] cpal::BuildStreamError),
+
+    #[error(transparent)]
+    PlayStreamError(#[from] cpal::PlayStreamError),
+
     #[error(transparent)]
     BindCodeError(#[from] bincode::Error),
 
     #[error(transparent)]
     ParseIntError(#[from] std::num::ParseIntError),
+
+    #[error(transparent)]
+    RandomError(#[from] getrandom::Error),
+
+    #[error(transparent)]
+    AddrParseError(#[from] std::net::AddrParseError),
 }
 
 impl ProxiChatError {
@@ -105,4 +234,23 @@ impl ProxiChatError {
 pub fn log_mark_error<T: std::error::Error + core::fmt::Display>(msg: &str, err: T) -> T {
     log::info!("{msg}: {err}");
     err
-}
\ No newline at end of file
+}
+
+/// the value following `name` in the launch arguments
+pub fn launch_arg(name: &str) -> Option<String> {
+    let mut args = env::args();
+    args.find(|arg| arg == name)?;
+    args.next()
+}
+
+/// the northstar profile folder, from `-profile=<name>` or R2Northstar
+pub fn profile_dir() -> PathBuf {
+    env::args()
+        .find_map(|arg| arg.strip_prefix("-profile=").map(PathBuf::from))
+        .unwrap_or_else(|| PathBuf::from("R2Northstar"))
+}
+
+/// per sample smoothing coefficient at `WIRE_SAMPLE_RATE` for a time constant in seconds
+pub fn time_coefficient(time: f32) -> f32 {
+    (-1. / (time * WIRE_SAMPLE_RATE as f32)).exp()
+}
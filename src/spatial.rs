@@ -0,0 +1,207 @@
+use rrplug::prelude::Vector3;
+use std::f32::consts::{FRAC_PI_4, TAU};
+
+use crate::{
+    server::PlayerState,
+    shared::{AudioSampleType, OUTPUT_CHANNELS, WIRE_SAMPLE_RATE},
+};
+
+/// delay of the far ear when a speaker is fully to one side, about 0.66 ms
+const MAX_INTERAURAL_DELAY: usize = (WIRE_SAMPLE_RATE as usize * 66) / 100_000;
+/// cutoff of the far ear when a speaker is fully to one side, the head is in the way
+const HEAD_SHADOW_CUTOFF: f32 = 1500.;
+/// cutoff of both ears when a speaker is right behind, voices sound duller from the back
+const REAR_CUTOFF: f32 = 4000.;
+/// cutoff of an ear that isn't shadowed at all
+const OPEN_CUTOFF: f32 = 20000.;
+
+/// the low pass state of both ears for one speaker, kept between frames so they join without clicks
+#[derive(Debug, Default, Clone, Copy)]
+pub struct EarFilters {
+    left: AudioSampleType,
+    right: AudioSampleType,
+}
+
+/// where a speaker is relative to a listener's view
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Direction {
+    /// -1 is fully left, 1 is fully right
+    pub pan: f32,
+    /// 1 is right in front, -1 right behind
+    pub facing: f32,
+}
+
+impl Direction {
+    pub const CENTER: Direction = Direction {
+        pan: 0.,
+        facing: 1.,
+    };
+
+    /// source's x is forward at a yaw of 0 and y is to the left
+    pub fn new(listener: &PlayerState, speaker: Vector3) -> Self {
+        let dx = speaker.x - listener.origin.x;
+        let dy = speaker.y - listener.origin.y;
+        if dx == 0. && dy == 0. {
+            return Self::CENTER;
+        }
+
+        let azimuth = dy.atan2(dx) - listener.angles.y.to_radians();
+
+        Self {
+            pan: -azimuth.sin(),
+            facing: azimuth.cos(),
+        }
+    }
+}
+
+/// adds `audio` to the interleaved stereo `mix` as if it came from `direction`
+///
+/// a cheap stand in for a hrtf: equal power panning, a delay and a low pass on the far ear
+/// and a low pass on both ears for speakers behind the listener.
+/// `previous` is the speaker's last frame, the delayed ear reads the end of it
+pub fn spatialize(
+    direction: Direction,
+    gain: f32,
+    audio: &[AudioSampleType],
+    previous: &[AudioSampleType],
+    filters: &mut EarFilters,
+    mix: &mut [AudioSampleType],
+) {
+    let angle = (direction.pan + 1.) * FRAC_PI_4;
+    let (left_gain, right_gain) = (angle.cos() * gain, angle.sin() * gain);
+
+    let side = direction.pan.abs();
+    let delay = (side * MAX_INTERAURAL_DELAY as f32).round() as usize;
+    let rear_cutoff = lerp(OPEN_CUTOFF, REAR_CUTOFF, (-direction.facing).max(0.));
+    let far_cutoff = lerp(rear_cutoff, HEAD_SHADOW_CUTOFF, side);
+
+    let (left_delay, left_cutoff, right_delay, right_cutoff) = if direction.pan > 0. {
+        (delay, far_cutoff, 0, rear_cutoff)
+    } else {
+        (0, rear_cutoff, delay, far_cutoff)
+    };
+    let (left_coefficient, right_coefficient) = (
+        low_pass_coefficient(left_cutoff),
+        low_pass_coefficient(right_cutoff),
+    );
+
+    for (i, frame) in mix.chunks_exact_mut(OUTPUT_CHANNELS).enumerate() {
+        filters.left += (delayed(audio, previous, i, left_delay) - filters.left) * left_coefficient;
+        filters.right +=
+            (delayed(audio, previous, i, right_delay) - filters.right) * right_coefficient;
+
+        frame[0] += filters.left * left_gain;
+        frame[1] += filters.right * right_gain;
+    }
+}
+
+/// adds `audio` to both channels of the interleaved `mix` the same
+pub fn add_centered(audio: &[AudioSampleType], gain: f32, mix: &mut [AudioSampleType]) {
+    for (frame, sample) in mix.chunks_exact_mut(OUTPUT_CHANNELS).zip(audio) {
+        frame.iter_mut().for_each(|mixed| *mixed += sample * gain);
+    }
+}
+
+fn delayed(
+    audio: &[AudioSampleType],
+    previous: &[AudioSampleType],
+    index: usize,
+    delay: usize,
+) -> AudioSampleType {
+    match index.checked_sub(delay) {
+        Some(index) => audio.get(index).copied(),
+        None => previous
+            .len()
+            .checked_sub(delay - index)
+            .and_then(|index| previous.get(index).copied()),
+    }
+    .unwrap_or_default()
+}
+
+fn low_pass_coefficient(cutoff: f32) -> f32 {
+    1. - (-TAU * cutoff / WIRE_SAMPLE_RATE as f32).exp()
+}
+
+fn lerp(a: f32, b: f32, t: f32) -> f32 {
+    a + (b - a) * t
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::shared::{AudioSampleVec, AUDIO_BUFFER_SIZE};
+
+    fn render(direction: Direction, audio: &[AudioSampleType]) -> AudioSampleVec {
+        let mut mix = vec![0.; audio.len() * OUTPUT_CHANNELS];
+        spatialize(
+            direction,
+            1.,
+            audio,
+            &[0.; AUDIO_BUFFER_SIZE],
+            &mut EarFilters::default(),
+            &mut mix,
+        );
+        mix
+    }
+
+    fn energy(mix: &[AudioSampleType], channel: usize) -> f32 {
+        mix.iter()
+            .skip(channel)
+            .step_by(OUTPUT_CHANNELS)
+            .map(|sample| sample * sample)
+            .sum()
+    }
+
+    fn first_sound(mix: &[AudioSampleType], channel: usize) -> Option<usize> {
+        mix.iter()
+            .skip(channel)
+            .step_by(OUTPUT_CHANNELS)
+            .position(|sample| *sample != 0.)
+    }
+
+    #[test]
+    fn pans_to_the_speaker() {
+        let audio = vec![0.5; AUDIO_BUFFER_SIZE];
+        let right = Direction {
+            pan: 0.8,
+            facing: 0.6,
+        };
+
+        let mix = render(right, &audio);
+        assert!(energy(&mix, 1) > energy(&mix, 0) * 4.);
+
+        let mix = render(Direction::CENTER, &audio);
+        assert!((energy(&mix, 0) - energy(&mix, 1)).abs() < 1e-3);
+    }
+
+    #[test]
+    fn delays_the_far_ear() {
+        let mut impulse = vec![0.; AUDIO_BUFFER_SIZE];
+        impulse[0] = 1.;
+        let delay = (0.5 * MAX_INTERAURAL_DELAY as f32).round() as usize;
+
+        for (pan, near, far) in [(0.5, 1, 0), (-0.5, 0, 1)] {
+            let mix = render(Direction { pan, facing: 0. }, &impulse);
+            assert_eq!(first_sound(&mix, near), Some(0));
+            assert_eq!(first_sound(&mix, far), Some(delay));
+        }
+    }
+
+    #[test]
+    fn muffles_speakers_behind() {
+        // as high as it gets, the low pass takes the most off of it
+        let audio = (0..AUDIO_BUFFER_SIZE)
+            .map(|i| if i % 2 == 0 { 0.5 } else { -0.5 })
+            .collect::<AudioSampleVec>();
+        let behind = Direction {
+            pan: 0.,
+            facing: -1.,
+        };
+
+        let front = render(Direction::CENTER, &audio);
+        let back = render(behind, &audio);
+        for channel in 0..OUTPUT_CHANNELS {
+            assert!(energy(&back, channel) < energy(&front, channel) / 2.);
+        }
+    }
+}
@@ -0,0 +1,151 @@
+use rrplug::prelude::*;
+
+use crate::{auth::AuthToken, exports::PLUGIN, server::PlayerState, shared::ProximityChatType};
+
+pub fn register_sq_functions(plugin_data: &PluginData) {
+    let functions = [
+        ("ProxiChat_GetVoicePort", info_get_voice_port as fn() -> _),
+        ("ProxiChat_GetAuthToken", info_get_auth_token),
+        ("ProxiChat_UpdatePlayer", info_update_player),
+        ("ProxiChat_RemovePlayer", info_remove_player),
+        ("ProxiChat_SetVoicePort", info_set_voice_port),
+        ("ProxiChat_SetAuthToken", info_set_auth_token),
+        ("ProxiChat_PopMessage", info_pop_message),
+    ];
+
+    for (name, info) in functions {
+        if let Err(err) = plugin_data.register_sq_functions(info) {
+            log::error!("couldn't register {name}: {err:?}");
+        }
+    }
+}
+
+/// the port clients should connect to for voice, 0 if the server isn't running
+#[rrplug::sqfunction(VM=Server,ExportName=ProxiChat_GetVoicePort)]
+fn get_voice_port() -> i32 {
+    let port = match &PLUGIN.wait().proximity_chat {
+        ProximityChatType::Server(server) => server
+            .lock()
+            .as_ref()
+            .and_then(|server| server.voice_port())
+            .unwrap_or_default(),
+        ProximityChatType::Client(_) => 0,
+    };
+
+    sq_return_int!(port as i32, sqvm, sq_functions);
+}
+
+/// the token the player's voice client has to send back, empty if the server isn't running
+#[rrplug::sqfunction(VM=Server,ExportName=ProxiChat_GetAuthToken)]
+fn get_auth_token(uid: String) -> String {
+    let mut token = String::new();
+
+    if let Some(uid) = parse_uid(&uid) {
+        PLUGIN
+            .wait()
+            .proximity_chat
+            .with_server(|server| match server.auth_token(uid) {
+                Ok(auth_token) => token = auth_token.to_string(),
+                Err(err) => log::error!("couldn't make an auth token for {uid}: {err}"),
+            });
+    }
+
+    sq_return_string!(token, sqvm, sq_functions);
+}
+
+/// called every frame for every player by the server scripts
+///
+/// `observer_target` is the uid of the spectated player or an empty string
+#[rrplug::sqfunction(VM=Server,ExportName=ProxiChat_UpdatePlayer)]
+fn update_player(
+    uid: String,
+    origin: Vector3,
+    angles: Vector3,
+    team: i32,
+    alive: bool,
+    observer_target: String,
+) {
+    let observer_target = match observer_target.as_str() {
+        "" => None,
+        target => parse_uid(target),
+    };
+
+    if let Some(uid) = parse_uid(&uid) {
+        PLUGIN.wait().proximity_chat.with_server(|server| {
+            server.update_player(
+                uid,
+                PlayerState {
+                    origin,
+                    angles,
+                    team,
+                    alive,
+                    observer_target,
+                },
+            )
+        });
+    }
+
+    sq_return_null!();
+}
+
+#[rrplug::sqfunction(VM=Server,ExportName=ProxiChat_RemovePlayer)]
+fn remove_player(uid: String) {
+    if let Some(uid) = parse_uid(&uid) {
+        PLUGIN
+            .wait()
+            .proximity_chat
+            .with_server(|server| server.remove_player(uid));
+    }
+
+    sq_return_null!();
+}
+
+/// the client scripts forward the port the server announced with `proxichat_voice_port`
+#[rrplug::sqfunction(VM=Client,ExportName=ProxiChat_SetVoicePort)]
+fn set_voice_port(port: String) {
+    match port.parse::<u16>() {
+        Ok(0) => log::info!("the server isn't running proximity chat"),
+        Ok(port) => {
+            if let ProximityChatType::Client(client) = &PLUGIN.wait().proximity_chat {
+                log::info!("server announced voice port {port}");
+
+                client.lock().set_voice_port(port);
+            }
+        }
+        Err(err) => log::error!("invalid voice port {port}: {err}"),
+    }
+
+    sq_return_null!();
+}
+
+/// the client scripts forward the token the server sent with `proxichat_auth_token`
+#[rrplug::sqfunction(VM=Client,ExportName=ProxiChat_SetAuthToken)]
+fn set_auth_token(token: String) {
+    match token.parse::<AuthToken>() {
+        Ok(token) => {
+            if let ProximityChatType::Client(client) = &PLUGIN.wait().proximity_chat {
+                client.lock().set_auth_token(token);
+            }
+        }
+        Err(err) => log::error!("invalid auth token: {err}"),
+    }
+
+    sq_return_null!();
+}
+
+/// the next message for the player, empty if there isn't one
+#[rrplug::sqfunction(VM=Client,ExportName=ProxiChat_PopMessage)]
+fn pop_message() -> String {
+    let message = match &PLUGIN.wait().proximity_chat {
+        ProximityChatType::Client(client) => client.lock().pop_message().unwrap_or_default(),
+        ProximityChatType::Server(_) => String::new(),
+    };
+
+    sq_return_string!(message, sqvm, sq_functions);
+}
+
+fn parse_uid(uid: &str) -> Option<i64> {
+    uid.parse()
+        .map_err(|err| log::warn!("squirrel gave an invalid uid {uid}: {err}"))
+        .ok()
+}
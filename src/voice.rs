@@ -0,0 +1,243 @@
+use bincode::Options;
+use serde::{Deserialize, Serialize};
+use std::{
+    io,
+    net::{SocketAddr, UdpSocket},
+};
+
+use crate::{
+    crypto::{OpeningKey, SealingKey},
+    shared::ProxiChatError,
+    spatial::Direction,
+};
+
+/// bigger than any datagram either side sends
+pub const VOICE_READ_BUFFER_SIZE: usize = 64 * 1024;
+/// the quietest speakers are left out of a frame past this many
+pub const MAX_SPEAKERS_PER_PACKET: usize = 16;
+/// or once their audio would take a datagram over this size, so it stays under the mtu
+pub const MAX_SPEAKERS_SIZE: usize = 1200;
+
+/// voice frames travel over udp so a lost frame doesn't hold back every frame after it
+#[derive(Debug, Serialize, Deserialize)]
+pub enum VoicePacket {
+    /// tells the server where to send voice to, also keeps nat mappings alive
+    Hello,
+    /// `audio` is a frame encoded with the codec agreed on during auth
+    ///
+    /// `radio` frames are sent to the whole team instead of only the players nearby
+    NewAudio {
+        sequence: u32,
+        radio: bool,
+        audio: Vec<u8>,
+    },
+    /// every speaker the listener hears this frame, silent ones are left out
+    ProccessedAudio {
+        sequence: u32,
+        speakers: Vec<SpeakerAudio>,
+    },
+}
+
+/// one speaker's mono frame, the client spatializes it and applies its own volume for them
+///
+/// every listener that hears a speaker with the same codec gets the same frame
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SpeakerAudio {
+    pub uid: i64,
+    pub placement: SpeakerPlacement,
+    pub audio: Vec<u8>,
+}
+
+/// where the listener hears a speaker from, quantized since it's sent for every speaker of every frame
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum SpeakerPlacement {
+    /// the same on both ears
+    Centered {
+        gain: u8,
+    },
+    Spatial {
+        gain: u8,
+        pan: i8,
+        facing: i8,
+    },
+}
+
+impl SpeakerPlacement {
+    pub fn new(gain: f32, direction: Option<Direction>) -> Self {
+        let gain = (gain.clamp(0., 1.) * u8::MAX as f32).round() as u8;
+        let quantize = |value: f32| (value.clamp(-1., 1.) * i8::MAX as f32).round() as i8;
+
+        match direction {
+            Some(direction) => SpeakerPlacement::Spatial {
+                gain,
+                pan: quantize(direction.pan),
+                facing: quantize(direction.facing),
+            },
+            None => SpeakerPlacement::Centered { gain },
+        }
+    }
+
+    pub fn gain(self) -> f32 {
+        match self {
+            SpeakerPlacement::Centered { gain } | SpeakerPlacement::Spatial { gain, .. } => {
+                gain as f32 / u8::MAX as f32
+            }
+        }
+    }
+
+    /// `None` if the speaker is centered
+    pub fn direction(self) -> Option<Direction> {
+        match self {
+            SpeakerPlacement::Centered { .. } => None,
+            SpeakerPlacement::Spatial { pan, facing, .. } => Some(Direction {
+                pan: pan as f32 / i8::MAX as f32,
+                facing: facing as f32 / i8::MAX as f32,
+            }),
+        }
+    }
+}
+
+/// what actually goes over the voice socket, `payload` is an encrypted `VoicePacket`
+///
+/// the session stays in the clear so the server knows whose key opens it
+#[derive(Debug, Serialize, Deserialize)]
+pub struct VoiceDatagram {
+    pub session: u64,
+    counter: u64,
+    payload: Vec<u8>,
+}
+
+impl VoiceDatagram {
+    pub fn open(mut self, key: &mut OpeningKey) -> Result<VoicePacket, ProxiChatError> {
+        key.open(self.counter, &mut self.payload)?;
+        Ok(bincode::options().deserialize(&self.payload)?)
+    }
+}
+
+/// keeps the first speakers that fit in a datagram, `speakers` has to be sorted loudest first
+pub fn limit_speakers(speakers: &mut Vec<SpeakerAudio>) {
+    let mut size = 0;
+    let count = speakers
+        .iter()
+        .take(MAX_SPEAKERS_PER_PACKET)
+        .take_while(|speaker| {
+            size = bincode::options()
+                .serialized_size(speaker)
+                .map_or(usize::MAX, |speaker_size| {
+                    size.saturating_add(speaker_size as usize)
+                });
+            size <= MAX_SPEAKERS_SIZE
+        })
+        .count();
+
+    speakers.truncate(count);
+}
+
+/// true if `sequence` comes after `last`, handles the counter wrapping around
+pub fn is_newer_sequence(sequence: u32, last: Option<u32>) -> bool {
+    match last {
+        Some(last) => (sequence.wrapping_sub(last) as i32) > 0,
+        None => true,
+    }
+}
+
+/// encrypts and sends a packet to the address the socket is connected to or to `addr`
+///
+/// voice packets use bincode's varint encoding, every length would take 8 bytes otherwise
+pub fn send_voice(
+    socket: &UdpSocket,
+    addr: Option<SocketAddr>,
+    session: u64,
+    packet: &VoicePacket,
+    key: &mut SealingKey,
+    buffer: &mut Vec<u8>,
+) -> Result<(), ProxiChatError> {
+    let mut payload = bincode::options().serialize(packet)?;
+    let counter = key.seal(&mut payload)?;
+
+    buffer.clear();
+    bincode::options().serialize_into(
+        &mut *buffer,
+        &VoiceDatagram {
+            session,
+            counter,
+            payload,
+        },
+    )?;
+
+    let result = match addr {
+        Some(addr) => socket.send_to(buffer, addr),
+        None => socket.send(buffer),
+    };
+
+    match result {
+        Ok(_) => Ok(()),
+        // a dropped voice frame is fine, the next one will make it
+        Err(err) if matches!(err.kind(), io::ErrorKind::WouldBlock) => Ok(()),
+        Err(err) => Err(err)?,
+    }
+}
+
+/// reads the next datagram, `None` once there is nothing left to read
+pub fn recv_voice(
+    socket: &UdpSocket,
+    buffer: &mut [u8],
+) -> Result<Option<(VoiceDatagram, SocketAddr)>, ProxiChatError> {
+    loop {
+        let (size, addr) = match socket.recv_from(buffer) {
+            Ok(result) => result,
+            Err(err) if matches!(err.kind(), io::ErrorKind::WouldBlock) => return Ok(None),
+            // windows reports icmp port unreachable from earlier sends like this
+            Err(err) if matches!(err.kind(), io::ErrorKind::ConnectionReset) => continue,
+            Err(err) => Err(err)?,
+        };
+
+        match bincode::options().deserialize(&buffer[..size]) {
+            Ok(packet) => return Ok(Some((packet, addr))),
+            Err(err) => log::warn!("dropping a malformed voice packet from {addr}: {err}"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn placements_survive_quantization() {
+        let direction = Direction {
+            pan: 0.5,
+            facing: -0.25,
+        };
+        let placement = SpeakerPlacement::new(0.3, Some(direction));
+
+        assert!((placement.gain() - 0.3).abs() < 1. / u8::MAX as f32);
+        let quantized = placement.direction().unwrap();
+        assert!((quantized.pan - direction.pan).abs() < 1. / i8::MAX as f32);
+        assert!((quantized.facing - direction.facing).abs() < 1. / i8::MAX as f32);
+
+        let centered = SpeakerPlacement::new(2., None);
+        assert_eq!(centered.gain(), 1.);
+        assert!(centered.direction().is_none());
+    }
+
+    #[test]
+    fn speakers_are_limited_to_a_datagram() {
+        let speaker = |uid, size| SpeakerAudio {
+            uid,
+            placement: SpeakerPlacement::new(1., None),
+            audio: vec![0; size],
+        };
+
+        let mut speakers = (0..MAX_SPEAKERS_PER_PACKET as i64 * 2)
+            .map(|uid| speaker(uid, 19))
+            .collect::<Vec<SpeakerAudio>>();
+        limit_speakers(&mut speakers);
+        assert_eq!(speakers.len(), MAX_SPEAKERS_PER_PACKET);
+
+        let mut speakers = (0..8).map(|uid| speaker(uid, 256)).collect();
+        limit_speakers(&mut speakers);
+        assert_eq!(speakers.len(), MAX_SPEAKERS_SIZE / 256);
+        assert_eq!(speakers[0].uid, 0);
+    }
+}
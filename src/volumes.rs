@@ -0,0 +1,198 @@
+use std::{collections::HashMap, fs, io, path::PathBuf};
+
+use crate::shared::{profile_dir, ProxiChatError};
+
+const VOLUMES_FILE: &str = "proxichat_volumes.txt";
+/// a speaker can be made at most this much louder
+pub const MAX_SPEAKER_VOLUME: f32 = 2.;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SpeakerVolume {
+    pub volume: f32,
+    pub muted: bool,
+}
+
+impl Default for SpeakerVolume {
+    fn default() -> Self {
+        Self {
+            volume: 1.,
+            muted: false,
+        }
+    }
+}
+
+impl SpeakerVolume {
+    pub fn gain(&self) -> f32 {
+        if self.muted {
+            0.
+        } else {
+            self.volume
+        }
+    }
+}
+
+/// the client's own volume for every speaker, saved as `<uid> <volume> [muted]` lines in the profile folder
+#[derive(Debug)]
+pub struct SpeakerVolumes {
+    path: PathBuf,
+    volumes: HashMap<i64, SpeakerVolume>,
+}
+
+impl SpeakerVolumes {
+    /// a missing file means everyone is at full volume
+    pub fn load() -> Self {
+        let path = profile_dir().join(VOLUMES_FILE);
+
+        let volumes = match fs::read_to_string(&path) {
+            Ok(volumes) => volumes
+                .lines()
+                .filter(|line| !line.trim().is_empty())
+                .filter_map(|line| {
+                    parse_line(line)
+                        .map_err(|err| log::warn!("invalid line in {}: {err}", path.display()))
+                        .ok()
+                })
+                .collect(),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => HashMap::new(),
+            Err(err) => {
+                log::error!("couldn't read {}: {err}", path.display());
+                HashMap::new()
+            }
+        };
+
+        Self { path, volumes }
+    }
+
+    pub fn get(&self, uid: i64) -> SpeakerVolume {
+        self.volumes.get(&uid).copied().unwrap_or_default()
+    }
+
+    /// refuses a volume that isn't a number, `clamp` would let nan through
+    pub fn set(&mut self, uid: i64, volume: SpeakerVolume) -> Result<(), ProxiChatError> {
+        if !volume.volume.is_finite() {
+            Err(ProxiChatError::InvalidVolume(volume.volume))?
+        }
+
+        let volume = SpeakerVolume {
+            volume: volume.volume.clamp(0., MAX_SPEAKER_VOLUME),
+            ..volume
+        };
+
+        if volume == SpeakerVolume::default() {
+            self.volumes.remove(&uid);
+        } else {
+            self.volumes.insert(uid, volume);
+        }
+
+        self.save()
+    }
+
+    fn save(&self) -> Result<(), ProxiChatError> {
+        let volumes = self
+            .volumes
+            .iter()
+            .map(|(uid, volume)| match volume.muted {
+                true => format!("{uid} {} muted\n", volume.volume),
+                false => format!("{uid} {}\n", volume.volume),
+            })
+            .collect::<String>();
+
+        Ok(fs::write(&self.path, volumes)?)
+    }
+}
+
+fn parse_line(line: &str) -> Result<(i64, SpeakerVolume), String> {
+    let mut parts = line.split_whitespace();
+
+    let uid = parts
+        .next()
+        .ok_or("missing uid")?
+        .parse::<i64>()
+        .map_err(|err| err.to_string())?;
+    let volume = parts
+        .next()
+        .ok_or("missing volume")?
+        .parse::<f32>()
+        .map_err(|err| err.to_string())?;
+    if !volume.is_finite() {
+        Err(format!("volume {volume} isn't a number"))?
+    }
+    let volume = volume.clamp(0., MAX_SPEAKER_VOLUME);
+    let muted = parts.next() == Some("muted");
+
+    Ok((uid, SpeakerVolume { volume, muted }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lines_round_trip() {
+        assert_eq!(
+            parse_line("1005 0.5"),
+            Ok((
+                1005,
+                SpeakerVolume {
+                    volume: 0.5,
+                    muted: false
+                }
+            ))
+        );
+        assert_eq!(
+            parse_line("-3 1.5 muted"),
+            Ok((
+                -3,
+                SpeakerVolume {
+                    volume: 1.5,
+                    muted: true
+                }
+            ))
+        );
+    }
+
+    #[test]
+    fn loaded_volumes_are_clamped() {
+        assert_eq!(
+            parse_line("1005 100"),
+            Ok((
+                1005,
+                SpeakerVolume {
+                    volume: MAX_SPEAKER_VOLUME,
+                    muted: false
+                }
+            ))
+        );
+        assert_eq!(
+            parse_line("1005 -1 muted"),
+            Ok((
+                1005,
+                SpeakerVolume {
+                    volume: 0.,
+                    muted: true
+                }
+            ))
+        );
+    }
+
+    #[test]
+    fn nan_is_refused() {
+        assert!(parse_line("1005 NaN").is_err());
+        assert!(parse_line("1005 inf").is_err());
+        assert!(parse_line("1005").is_err());
+
+        let mut volumes = SpeakerVolumes {
+            path: std::env::temp_dir().join("proxichat_volumes_test.txt"),
+            volumes: HashMap::new(),
+        };
+        let nan = SpeakerVolume {
+            volume: f32::NAN,
+            muted: false,
+        };
+        assert!(matches!(
+            volumes.set(1005, nan),
+            Err(ProxiChatError::InvalidVolume(_))
+        ));
+        assert_eq!(volumes.get(1005), SpeakerVolume::default());
+    }
+}